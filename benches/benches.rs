@@ -1,19 +1,18 @@
 use criterion::{criterion_group, criterion_main, Bencher, Criterion};
 use crossbeam::channel;
 use kvs::client::Client;
+use kvs::server::{self, ServerOptions};
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::KvsEngine;
-use kvs::{server, KvStore, SledKvsEngine};
+use kvs::{KvStore, KvStoreOptions};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use std::io::Write;
 use std::net::TcpStream;
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
-use tracing::{debug, error, info, trace};
-use tracing_subscriber;
+use tokio::sync::oneshot;
+use tracing::{debug, error, trace};
 
 const NUM_CLIENT: usize = 200;
 const SERVER_ADDR: &str = "127.0.0.1:4000";
@@ -24,11 +23,7 @@ pub fn thread_pool_bench(c: &mut Criterion) {
     let mut rng = thread_rng();
     let mut data: Vec<String> = Vec::new();
     for _ in 0..NUM_CLIENT {
-        let key: String = rng
-            .sample_iter(&Alphanumeric)
-            .take(1000)
-            .map(char::from)
-            .collect();
+        let key: String = rng.sample_iter(&Alphanumeric).take(1000).collect();
         data.push(key);
     }
     // let data2 = data.clone();
@@ -44,16 +39,25 @@ pub fn thread_pool_bench(c: &mut Criterion) {
     );
 }
 
-fn concurrent_bench(b: &mut Bencher, num: u32, data: &Vec<String>, is_write: bool) {
+fn concurrent_bench(b: &mut Bencher, num: u32, data: &[String], is_write: bool) {
     debug!("bench for {} thread pool", num);
     // start server
     let dir = TempDir::new().unwrap();
+    let (stop, stopped) = oneshot::channel();
     let server_handle = thread::spawn(move || {
-        server::run(
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let pool = SharedQueueThreadPool::new(num).unwrap();
+        let store = KvStore::open(dir.path(), pool, KvStoreOptions::new()).unwrap();
+        if let Err(e) = rt.block_on(server::run(
             SERVER_ADDR,
-            KvStore::open(dir.path(), SharedQueueThreadPool::new(num).unwrap()).unwrap(),
-        )
-        .await;
+            store,
+            ServerOptions::new(),
+            async {
+                let _ = stopped.await;
+            },
+        )) {
+            error!("server exited with error: {}", e);
+        }
     });
 
     // prepare client thread
@@ -98,18 +102,9 @@ fn concurrent_bench(b: &mut Bencher, num: u32, data: &Vec<String>, is_write: boo
     });
 
     // shutdown server
-    loop {
-        let stream = TcpStream::connect(SERVER_ADDR);
-        if let Err(e) = stream {
-            error!("failed to connnect to server, try again: {}", e);
-            thread::sleep(Duration::from_secs(1));
-            continue;
-        }
-        stream.unwrap().write_all(&['!' as u8]);
-        debug!("waiting for server to exited");
-        server_handle.join().unwrap();
-        break;
-    }
+    stop.send(()).unwrap();
+    debug!("waiting for server to exited");
+    server_handle.join().unwrap();
     debug!("server exited already. terminate clients now");
 
     // terminate clients thread
@@ -130,7 +125,7 @@ fn client_write_heavy(k: String) {
             Err(e) => error!("client failed to connect server {}", e),
             Ok(stream) => {
                 let mut cli = Client::new(stream);
-                if let Err(e) = cli.set(k.to_owned(), k.to_owned()) {
+                if let Err(e) = cli.set(k.clone().into_bytes(), k.clone().into_bytes()) {
                     error!("failed to set {}", e);
                 }
             }
@@ -144,7 +139,7 @@ fn client_read_heavy(k: String) {
         Err(e) => error!("client failed to connect server {}", e),
         Ok(stream) => {
             let mut cli = Client::new(stream);
-            if let Err(e) = cli.set(k.to_owned(), k.to_owned()) {
+            if let Err(e) = cli.set(k.clone().into_bytes(), k.clone().into_bytes()) {
                 error!("failed to set {}", e);
             }
         }
@@ -155,14 +150,13 @@ fn client_read_heavy(k: String) {
             Err(e) => error!("client failed to connect server {}", e),
             Ok(stream) => {
                 let mut cli = Client::new(stream);
-                match cli.get(k.to_owned()) {
+                match cli.get(k.clone().into_bytes()) {
                     Err(e) => {
                         error!("failed to get {}", e);
                     }
-                    Ok(mut ret) => {
-                        ret.pop();
-                        if ret != k {
-                            error!("expect: {}\ngot: {}", k, ret);
+                    Ok(ret) => {
+                        if ret.as_deref() != Some(k.as_bytes()) {
+                            error!("expect: {}\ngot: {:?}", k, ret);
                         }
                     }
                 }
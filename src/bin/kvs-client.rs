@@ -1,15 +1,23 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 use kvs::client::Client;
 use kvs::Result;
+use std::io::{self, Write};
 use std::net::TcpStream;
 use std::process::exit;
+use std::time::Duration;
 
 const ARG_KEY: &str = "key";
 const ARG_VAL: &str = "value";
+const ARG_START: &str = "start";
+const ARG_END: &str = "end";
+const ARG_PREFIX: &str = "prefix";
+const ARG_LIMIT: &str = "limit";
+const ARG_TTL: &str = "ttl";
 
 const CMD_SET: &str = "set";
 const CMD_GET: &str = "get";
 const CMD_RM: &str = "rm";
+const CMD_SCAN: &str = "scan";
 
 fn main() -> Result<()> {
     let m = Command::new("kvs-client")
@@ -20,15 +28,20 @@ fn main() -> Result<()> {
             Command::new(CMD_SET)
                 .about("Insert/Update key value")
                 .args(&[
-                    Arg::new(ARG_KEY),
-                    Arg::new(ARG_VAL),
+                    Arg::new(ARG_KEY).allow_invalid_utf8(true),
+                    Arg::new(ARG_VAL).allow_invalid_utf8(true),
+                    Arg::new(ARG_TTL)
+                        .long("ttl")
+                        .takes_value(true)
+                        .help("Expire the key after this many seconds")
+                        .validator(parse_ttl),
                     Arg::new("addr")
                         .long("addr")
                         .default_value("127.0.0.1:4000"),
                 ]),
             Command::new(CMD_GET)
                 .about("Get value by key")
-                .arg(Arg::new(ARG_KEY))
+                .arg(Arg::new(ARG_KEY).allow_invalid_utf8(true))
                 .arg(
                     Arg::new("addr")
                         .long("addr")
@@ -36,12 +49,32 @@ fn main() -> Result<()> {
                 ),
             Command::new(CMD_RM)
                 .about("Remove value by key")
-                .arg(Arg::new(ARG_KEY))
+                .arg(Arg::new(ARG_KEY).allow_invalid_utf8(true))
                 .arg(
                     Arg::new("addr")
                         .long("addr")
                         .default_value("127.0.0.1:4000"),
                 ),
+            Command::new(CMD_SCAN)
+                .about("List key values in [start, end) or with prefix, in key order")
+                .args(&[
+                    Arg::new(ARG_START)
+                        .default_value("")
+                        .allow_invalid_utf8(true),
+                    Arg::new(ARG_END).allow_invalid_utf8(true),
+                    Arg::new(ARG_PREFIX)
+                        .long("prefix")
+                        .takes_value(true)
+                        .allow_invalid_utf8(true)
+                        .conflicts_with_all(&[ARG_START, ARG_END]),
+                    Arg::new(ARG_LIMIT)
+                        .long("limit")
+                        .takes_value(true)
+                        .validator(|s| s.parse::<usize>()),
+                    Arg::new("addr")
+                        .long("addr")
+                        .default_value("127.0.0.1:4000"),
+                ]),
         ])
         .after_help("--Over--")
         .get_matches();
@@ -49,20 +82,30 @@ fn main() -> Result<()> {
     match m.subcommand() {
         Some((CMD_SET, sub_m)) => {
             let mut client = Client::new(TcpStream::connect(sub_m.value_of("addr").unwrap())?);
-            let key = sub_m.value_of(ARG_KEY).unwrap().to_owned();
-            let val = sub_m.value_of(ARG_VAL).unwrap().to_owned();
-            client.set(key, val)
+            let key = arg_bytes(sub_m, ARG_KEY).unwrap();
+            let val = arg_bytes(sub_m, ARG_VAL).unwrap();
+            match sub_m.value_of(ARG_TTL) {
+                Some(ttl) => client.set_with_ttl(key, val, parse_ttl(ttl)?)?,
+                None => client.set(key, val)?,
+            }
+            Ok(())
         }
         Some((CMD_GET, sub_m)) => {
             let mut client = Client::new(TcpStream::connect(sub_m.value_of("addr").unwrap())?);
-            let key = sub_m.value_of(ARG_KEY).unwrap().to_owned();
-            let content = client.get(key)?;
-            print!("{}", content);
+            let key = arg_bytes(sub_m, ARG_KEY).unwrap();
+            match client.get(key)? {
+                Some(val) => {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&val)?;
+                    stdout.write_all(b"\n")?;
+                }
+                None => print!("Key not found"),
+            }
             Ok(())
         }
         Some((CMD_RM, sub_m)) => {
             let mut client = Client::new(TcpStream::connect(sub_m.value_of("addr").unwrap())?);
-            let key = sub_m.value_of(ARG_KEY).unwrap().to_owned();
+            let key = arg_bytes(sub_m, ARG_KEY).unwrap();
             let found = client.remove(key)?;
             if !found {
                 eprint!("Key not found");
@@ -70,9 +113,51 @@ fn main() -> Result<()> {
             }
             Ok(())
         }
+        Some((CMD_SCAN, sub_m)) => {
+            let mut client = Client::new(TcpStream::connect(sub_m.value_of("addr").unwrap())?);
+            let limit = match sub_m.value_of(ARG_LIMIT) {
+                Some(limit) => limit.parse()?,
+                None => usize::MAX,
+            };
+            let scan = match arg_bytes(sub_m, ARG_PREFIX) {
+                Some(prefix) => client.scan_prefix(prefix, limit)?,
+                None => {
+                    let start = arg_bytes(sub_m, ARG_START).unwrap();
+                    let end = arg_bytes(sub_m, ARG_END);
+                    client.scan(start, end, limit)?
+                }
+            };
+            let mut stdout = io::stdout().lock();
+            for pair in scan {
+                let (key, val) = pair?;
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&val)?;
+                stdout.write_all(b"\n")?;
+            }
+            Ok(())
+        }
         _ => {
             eprintln!("arguments needed, use --help to get more information");
             exit(1);
         }
     }
 }
+
+/// Positive seconds, maybe fractional
+fn parse_ttl(s: &str) -> Result<Duration> {
+    match Duration::try_from_secs_f64(s.parse()?) {
+        Ok(ttl) if !ttl.is_zero() => Ok(ttl),
+        _ => Err(failure::err_msg("ttl must be a positive number of seconds")),
+    }
+}
+
+/// Raw bytes of an argument, which may not be UTF-8 on unix
+fn arg_bytes(m: &ArgMatches, name: &str) -> Option<Vec<u8>> {
+    let arg = m.value_of_os(name)?;
+    #[cfg(unix)]
+    let bytes = std::os::unix::ffi::OsStrExt::as_bytes(arg).to_vec();
+    #[cfg(not(unix))]
+    let bytes = arg.to_string_lossy().into_owned().into_bytes();
+    Some(bytes)
+}
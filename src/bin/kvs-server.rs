@@ -1,12 +1,13 @@
 use clap::{Arg, Command};
-use kvs::server::run;
+use kvs::server::{run, Protocol, ServerOptions};
 use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{KvStore, MyErr, Result, SledKvsEngine};
-use num_cpus;
+use kvs::{http, KvStore, KvStoreOptions, KvsEngine, MyErr, Result, SledKvsEngine, SyncPolicy};
 use std::fs::read_dir;
-use tokio;
-use tracing::{debug, error};
-use tracing_subscriber;
+use std::future;
+use std::time::Duration;
+use tokio::signal;
+use tokio::sync::watch;
+use tracing::{debug, error, info};
 
 const DEFAULT_DIR: &str = ".";
 
@@ -33,9 +34,102 @@ async fn main() -> Result<()> {
                 .possible_values(["naive", "better"])
                 .default_value("better"),
         )
+        .arg(
+            Arg::new("segment-size")
+                .long("segment-size")
+                .takes_value(true)
+                .help("Size in bytes at which a segment is cut (kvs engine)"),
+        )
+        .arg(
+            Arg::new("compact-threshold")
+                .long("compact-threshold")
+                .takes_value(true)
+                .help("Stale bytes that trigger a compaction (kvs engine)"),
+        )
+        .arg(
+            Arg::new("compact-interval")
+                .long("compact-interval")
+                .takes_value(true)
+                .help("Milliseconds between compaction checks (kvs engine)"),
+        )
+        .arg(
+            Arg::new("sync")
+                .long("sync")
+                .possible_values(["always", "interval", "never"])
+                .default_value("always")
+                .help("When to fsync written data (kvs engine)"),
+        )
+        .arg(
+            Arg::new("sync-interval")
+                .long("sync-interval")
+                .default_value("1000")
+                .help("Milliseconds between syncs if --sync=interval"),
+        )
+        .arg(
+            Arg::new("protocol")
+                .long("protocol")
+                .possible_values(["kvs", "resp"])
+                .default_value("kvs")
+                .help("Protocol to serve, resp is compatible with Redis clients"),
+        )
+        .arg(
+            Arg::new("http-addr")
+                .long("http-addr")
+                .takes_value(true)
+                .help("Also serve an HTTP/JSON gateway on this address"),
+        )
+        .arg(
+            Arg::new("idle-timeout")
+                .long("idle-timeout")
+                .takes_value(true)
+                .help("Milliseconds after which an idle connection is closed"),
+        )
+        .arg(
+            Arg::new("read-timeout")
+                .long("read-timeout")
+                .takes_value(true)
+                .help("Milliseconds after which a connection stuck receiving a request is closed"),
+        )
+        .arg(
+            Arg::new("write-timeout")
+                .long("write-timeout")
+                .takes_value(true)
+                .help("Milliseconds after which a connection stuck sending a response is closed"),
+        )
+        .arg(
+            Arg::new("max-connections")
+                .long("max-connections")
+                .takes_value(true)
+                .help("Most connections served at a time, others are refused"),
+        )
+        .arg(
+            Arg::new("max-key-size")
+                .long("max-key-size")
+                .takes_value(true)
+                .help("Longest key accepted in bytes"),
+        )
+        .arg(
+            Arg::new("max-value-size")
+                .long("max-value-size")
+                .takes_value(true)
+                .help("Longest value accepted in bytes"),
+        )
+        .arg(
+            Arg::new("max-request-size")
+                .long("max-request-size")
+                .takes_value(true)
+                .help("Longest request accepted in bytes"),
+        )
+        .arg(
+            Arg::new("drain-timeout")
+                .long("drain-timeout")
+                .takes_value(true)
+                .help("Milliseconds to wait for requests in flight on SIGINT or SIGTERM"),
+        )
         .after_help("--Over--")
         .get_matches();
     let addr = m.value_of("addr").unwrap();
+    let http_addr = m.value_of("http-addr");
     let mut eng = "kvs".to_owned();
     if let Some(e) = m.value_of("engine") {
         eng = e.to_owned();
@@ -49,6 +143,35 @@ async fn main() -> Result<()> {
         eng = last;
     }
     let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let protocol = match m.value_of("protocol").unwrap() {
+        "resp" => Protocol::Resp,
+        _ => Protocol::Kvs,
+    };
+    let mut server_opts = ServerOptions::new().protocol(protocol);
+    if let Some(ms) = m.value_of("idle-timeout") {
+        server_opts = server_opts.idle_timeout(Duration::from_millis(ms.parse()?));
+    }
+    if let Some(ms) = m.value_of("read-timeout") {
+        server_opts = server_opts.read_timeout(Duration::from_millis(ms.parse()?));
+    }
+    if let Some(ms) = m.value_of("write-timeout") {
+        server_opts = server_opts.write_timeout(Duration::from_millis(ms.parse()?));
+    }
+    if let Some(max) = m.value_of("max-connections") {
+        server_opts = server_opts.max_connections(max.parse()?);
+    }
+    if let Some(len) = m.value_of("max-key-size") {
+        server_opts = server_opts.max_key_len(len.parse()?);
+    }
+    if let Some(len) = m.value_of("max-value-size") {
+        server_opts = server_opts.max_val_len(len.parse()?);
+    }
+    if let Some(len) = m.value_of("max-request-size") {
+        server_opts = server_opts.max_request_len(len.parse()?);
+    }
+    if let Some(ms) = m.value_of("drain-timeout") {
+        server_opts = server_opts.drain_timeout(Duration::from_millis(ms.parse()?));
+    }
     eprintln!(
         "kvs-server[v{}] starting...addr={}, engine={}",
         env!("CARGO_PKG_VERSION"),
@@ -56,14 +179,91 @@ async fn main() -> Result<()> {
         eng
     );
     if eng == "kvs" {
-        run(addr, KvStore::open(DEFAULT_DIR, pool)?).await
+        let mut opts = KvStoreOptions::new();
+        if let Some(size) = m.value_of("segment-size") {
+            opts = opts.segment_size(size.parse()?);
+        }
+        if let Some(threshold) = m.value_of("compact-threshold") {
+            opts = opts.compact_threshold(threshold.parse()?);
+        }
+        if let Some(ms) = m.value_of("compact-interval") {
+            opts = opts.compact_check(Duration::from_millis(ms.parse()?));
+        }
+        let sync = match m.value_of("sync").unwrap() {
+            "always" => SyncPolicy::Always,
+            "interval" => {
+                let ms = m.value_of("sync-interval").unwrap().parse()?;
+                SyncPolicy::Interval(Duration::from_millis(ms))
+            }
+            _ => SyncPolicy::Never,
+        };
+        let eng = KvStore::open(DEFAULT_DIR, pool, opts.sync(sync))?;
+        serve(addr, http_addr, eng, server_opts).await
     } else if eng == "sled" {
-        run(addr, SledKvsEngine::open(DEFAULT_DIR)?).await
+        let eng = SledKvsEngine::open(DEFAULT_DIR)?;
+        serve(addr, http_addr, eng, server_opts).await
     } else {
         panic!("never execute")
     }
 }
 
+async fn serve<E: KvsEngine>(
+    addr: &str,
+    http_addr: Option<&str>,
+    eng: E,
+    opts: ServerOptions,
+) -> Result<()> {
+    match http_addr {
+        Some(http_addr) => {
+            let (stop, stopping) = watch::channel(false);
+            let stopped = |mut stopping: watch::Receiver<bool>| async move {
+                let _ = stopping.wait_for(|stop| *stop).await;
+            };
+            let signal = async move {
+                shutdown_signal().await;
+                stop.send_replace(true);
+                Ok(())
+            };
+            tokio::try_join!(
+                signal,
+                run(addr, eng.clone(), opts.clone(), stopped(stopping.clone())),
+                http::run(http_addr, eng, opts, stopped(stopping)),
+            )?;
+            Ok(())
+        }
+        None => run(addr, eng, opts, shutdown_signal()).await,
+    }
+}
+
+/// Resolve on SIGINT or SIGTERM
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = signal::ctrl_c().await {
+            error!("failed to listen to SIGINT: {}", e);
+            future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                error!("failed to listen to SIGTERM: {}", e);
+                future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = future::pending::<()>();
+    tokio::select! {
+        () = interrupt => {}
+        () = terminate => {}
+    }
+    info!("shutdown signal received");
+}
+
 fn last_engine() -> Result<Option<String>> {
     for entry in read_dir(DEFAULT_DIR)? {
         let entry = entry?;
@@ -0,0 +1,329 @@
+use super::{
+    check_ack, closed, done, found, hello, refused, scan_page, swapped, unknown_id, value,
+    AsyncPipeline, ClientError, ClientOptions, ClientResult, Pipeline,
+};
+use crate::protocol::{self, Command, Reply, Request, Response};
+use crate::WriteBatch;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use std::mem;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::time::timeout;
+use tracing::debug;
+
+/// Bytes reserved for each read from the connection
+const READ_CHUNK: usize = 8 * 1024;
+
+/// Client speaking protocol v2 on tokio, its operations are those of
+/// `Client`.
+///
+/// Every operation is cancellation safe: dropping its future, for example by
+/// a timeout, leaves the connection usable. Bytes of a request partially
+/// written are sent before the next one, and responses to cancelled requests
+/// are skipped.
+pub struct AsyncClient {
+    reader: FrameReader,
+    writer: FrameWriter,
+    next_id: u32,
+    request_timeout: Option<Duration>,
+    /// Whether the connection is closed or out of sync after an error
+    broken: bool,
+}
+
+/// Read side of a connection
+struct FrameReader {
+    stream: OwnedReadHalf,
+    /// Bytes read but not decoded yet
+    buf: Vec<u8>,
+    /// Requests whose responses are not fully read yet
+    in_flight: HashSet<u32>,
+}
+
+/// Write side of a connection
+struct FrameWriter {
+    stream: OwnedWriteHalf,
+    /// Bytes of requests not written yet
+    buf: Vec<u8>,
+}
+
+impl AsyncClient {
+    pub async fn connect<A: ToSocketAddrs>(addr: A) -> ClientResult<Self> {
+        Self::connect_with(addr, ClientOptions::new()).await
+    }
+    pub async fn connect_with<A: ToSocketAddrs>(
+        addr: A,
+        opts: ClientOptions,
+    ) -> ClientResult<Self> {
+        let connect = async {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            let (mut reader, mut writer) = stream.into_split();
+            writer.write_all(&hello()).await?;
+            let mut ack = [0; 5];
+            reader.read_exact(&mut ack).await?;
+            if check_ack(&ack)? {
+                let len = reader.read_u32().await?.into();
+                let mut msg = Vec::new();
+                (&mut reader).take(len).read_to_end(&mut msg).await?;
+                return Err(refused(&msg));
+            }
+            Ok(AsyncClient {
+                reader: FrameReader {
+                    stream: reader,
+                    buf: Vec::new(),
+                    in_flight: HashSet::new(),
+                },
+                writer: FrameWriter {
+                    stream: writer,
+                    buf: Vec::new(),
+                },
+                next_id: 0,
+                request_timeout: opts.request_timeout,
+                broken: false,
+            })
+        };
+        with_timeout(opts.connect_timeout, connect).await
+    }
+    pub async fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<()> {
+        done(self.call(Command::Set { key, val }).await?)
+    }
+    pub async fn set_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    ) -> ClientResult<()> {
+        done(self.call(Command::SetWithTtl { key, val, ttl }).await?)
+    }
+    pub async fn remove(&mut self, key: Vec<u8>) -> ClientResult<bool> {
+        found(self.call(Command::Remove { key }).await?)
+    }
+    pub async fn get(&mut self, key: Vec<u8>) -> ClientResult<Option<Vec<u8>>> {
+        value(self.call(Command::Get { key }).await?)
+    }
+    pub async fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> ClientResult<bool> {
+        swapped(
+            self.call(Command::CompareAndSwap { key, expected, new })
+                .await?,
+        )
+    }
+    pub async fn set_if_absent(&mut self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<bool> {
+        self.compare_and_swap(key, None, Some(val)).await
+    }
+    pub async fn apply_batch(&mut self, batch: WriteBatch) -> ClientResult<()> {
+        done(self.call(Command::Batch { batch }).await?)
+    }
+    pub async fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> ClientResult<AsyncScan<'_>> {
+        let limit = limit as u64;
+        let id = self.send(Command::Scan { start, end, limit }).await?;
+        Ok(AsyncScan::new(self, id))
+    }
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: Vec<u8>,
+        limit: usize,
+    ) -> ClientResult<AsyncScan<'_>> {
+        let limit = limit as u64;
+        let id = self.send(Command::ScanPrefix { prefix, limit }).await?;
+        Ok(AsyncScan::new(self, id))
+    }
+    pub fn pipeline(&mut self) -> AsyncPipeline<'_> {
+        Pipeline::new(self)
+    }
+    pub async fn ping(&mut self) -> ClientResult<()> {
+        done(self.call(Command::Ping).await?)
+    }
+    pub async fn close(mut self) -> ClientResult<()> {
+        done(self.call(Command::Close).await?)
+    }
+    pub(super) fn is_broken(&self) -> bool {
+        self.broken
+    }
+    async fn call(&mut self, cmd: Command) -> ClientResult<Reply> {
+        let id = self.queue(cmd);
+        let (reader, writer) = (&mut self.reader, &mut self.writer);
+        let reply = with_timeout(self.request_timeout, async {
+            writer.flush().await?;
+            reader.recv(id).await
+        })
+        .await;
+        self.check(reply)
+    }
+    async fn send(&mut self, cmd: Command) -> ClientResult<u32> {
+        let id = self.queue(cmd);
+        let sent = with_timeout(self.request_timeout, self.writer.flush()).await;
+        self.check(sent)?;
+        Ok(id)
+    }
+    /// Mark the connection broken by errors other than a failed or timed out
+    /// request, which leave it in sync
+    fn check<T>(&mut self, result: ClientResult<T>) -> ClientResult<T> {
+        if let Err(e) = &result {
+            if !e.is_request_failure() && !matches!(e, ClientError::Timeout) {
+                self.broken = true;
+            }
+        }
+        result
+    }
+    /// Buffer a request to be written by the next flush
+    fn queue(&mut self, cmd: Command) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.reader.in_flight.insert(id);
+        self.writer
+            .buf
+            .extend_from_slice(&Request { id, cmd }.encode());
+        id
+    }
+}
+
+impl FrameWriter {
+    /// Write buffered requests, it can be cancelled and resumed
+    async fn flush(&mut self) -> ClientResult<()> {
+        while !self.buf.is_empty() {
+            let n = self.stream.write(&self.buf).await?;
+            if n == 0 {
+                Err(closed())?
+            }
+            self.buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl FrameReader {
+    /// Receive the reply of request `id`, skipping responses to requests
+    /// cancelled before it
+    async fn recv(&mut self, id: u32) -> ClientResult<Reply> {
+        loop {
+            let frame = self.read_frame().await?;
+            let resp = Response::decode(&frame)?;
+            debug!(
+                "response of request {} received: {:?}",
+                resp.id,
+                resp.reply.status()
+            );
+            if resp.id != id && !self.in_flight.contains(&resp.id) {
+                Err(unknown_id(resp.id))?
+            }
+            // a scan is answered by pages until its last response
+            if !matches!(resp.reply, Reply::Pairs(_)) {
+                self.in_flight.remove(&resp.id);
+            }
+            if resp.id == id {
+                return Ok(resp.reply);
+            }
+        }
+    }
+    /// Read a frame, it can be cancelled and resumed
+    async fn read_frame(&mut self) -> ClientResult<Vec<u8>> {
+        loop {
+            if let Some(frame) = protocol::take_frame(&mut self.buf) {
+                return Ok(frame);
+            }
+            self.buf.reserve(READ_CHUNK);
+            if self.stream.read_buf(&mut self.buf).await? == 0 {
+                Err(closed())?
+            }
+        }
+    }
+}
+
+/// Run `fut` within `limit` if any
+async fn with_timeout<T>(
+    limit: Option<Duration>,
+    fut: impl Future<Output = ClientResult<T>>,
+) -> ClientResult<T> {
+    match limit {
+        Some(limit) => timeout(limit, fut)
+            .await
+            .map_err(|_| ClientError::Timeout)?,
+        None => fut.await,
+    }
+}
+
+impl AsyncPipeline<'_> {
+    pub async fn execute(&mut self) -> ClientResult<Vec<Reply>> {
+        let cmds = mem::take(&mut self.cmds);
+        let client = &mut *self.client;
+        let ids = cmds
+            .into_iter()
+            .map(|cmd| client.queue(cmd))
+            .collect::<Vec<_>>();
+        let (reader, writer) = (&mut client.reader, &mut client.writer);
+        // requests are written while replies are read, so that neither side
+        // blocks on a full socket buffer
+        let recv_all = async {
+            let mut replies = Vec::with_capacity(ids.len());
+            for &id in &ids {
+                replies.push(reader.recv(id).await?);
+            }
+            Ok(replies)
+        };
+        let exec = async {
+            let (_, replies) = tokio::try_join!(writer.flush(), recv_all)?;
+            Ok(replies)
+        };
+        let replies = with_timeout(client.request_timeout, exec).await;
+        client.check(replies)
+    }
+}
+
+/// Pairs streamed by a scan
+pub struct AsyncScan<'a> {
+    client: &'a mut AsyncClient,
+    id: u32,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl<'a> AsyncScan<'a> {
+    fn new(client: &'a mut AsyncClient, id: u32) -> Self {
+        AsyncScan {
+            client,
+            id,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+    /// Next pair, `None` once the scan is done
+    pub async fn next(&mut self) -> Option<ClientResult<(Vec<u8>, Vec<u8>)>> {
+        while self.page.is_empty() {
+            if self.done {
+                return None;
+            }
+            let recv = self.client.reader.recv(self.id);
+            let page = with_timeout(self.client.request_timeout, recv).await;
+            match self.client.check(page).and_then(scan_page) {
+                Ok(Some(pairs)) => self.page.extend(pairs),
+                Ok(None) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+    /// Read all remaining pairs
+    pub async fn collect(mut self) -> ClientResult<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = Vec::new();
+        while let Some(pair) = self.next().await {
+            pairs.push(pair?);
+        }
+        Ok(pairs)
+    }
+}
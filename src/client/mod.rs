@@ -0,0 +1,545 @@
+//! Clients speaking protocol v2, blocking `Client` and `AsyncClient`. Both
+//! share the codec of `protocol`, the building of pipelines and the
+//! interpretation of replies here. Operations are documented on `Client`.
+mod async_client;
+mod pool;
+
+pub use async_client::{AsyncClient, AsyncScan};
+pub use pool::{AsyncClientPool, ClientPool, PoolOptions, PooledAsyncClient, PooledClient};
+
+use crate::protocol::{self, Command, Reply, Request, Response, Status};
+use crate::WriteBatch;
+use std::collections::{HashSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::mem;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::panic;
+use std::result;
+use std::thread;
+use std::time::Duration;
+use tracing::debug;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Pairs = Vec<(Vec<u8>, Vec<u8>)>;
+
+pub type ClientResult<T> = result::Result<T, ClientError>;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// Failed to connect, read or write, or the connection is closed
+    Transport(io::Error),
+    /// The server replied something malformed or unexpected
+    Protocol(String),
+    /// The server failed the request, the status is `BadRequest` or
+    /// `Internal`. Or it refused the connection with `Unavailable`, then it may
+    /// be retried later
+    Server(Status, String),
+    /// The request did not complete in time
+    Timeout,
+}
+
+impl ClientError {
+    /// Whether only the request failed, leaving the connection usable
+    fn is_request_failure(&self) -> bool {
+        matches!(self, ClientError::Server(status, _) if *status != Status::Unavailable)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::Transport(e) => write!(f, "Transport error: {}", e),
+            ClientError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            ClientError::Server(status, msg) => write!(f, "Server error {:?}: {}", status, msg),
+            ClientError::Timeout => write!(f, "Timed out"),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ClientError::Transport(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            // a blocking socket reports its timeouts by these
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ClientError::Timeout,
+            _ => ClientError::Transport(e),
+        }
+    }
+}
+
+/// Errors of the codec are either IO errors or malformed frames
+impl From<failure::Error> for ClientError {
+    fn from(e: failure::Error) -> Self {
+        match e.downcast::<io::Error>() {
+            Ok(e) => e.into(),
+            Err(e) => ClientError::Protocol(e.to_string()),
+        }
+    }
+}
+
+fn closed() -> ClientError {
+    ClientError::Transport(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by server",
+    ))
+}
+
+/// Options of connecting a `Client` or an `AsyncClient`
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+}
+
+impl Default for ClientOptions {
+    fn default() -> Self {
+        ClientOptions {
+            connect_timeout: Some(CONNECT_TIMEOUT),
+            request_timeout: None,
+        }
+    }
+}
+
+impl ClientOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Limit of connecting and handshaking
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+    /// Limit of a request, or of a page of a scan. No limit by default
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+}
+
+/// Client speaking protocol v2
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    next_id: u32,
+    /// Whether the handshake is sent
+    greeted: bool,
+    /// Whether the handshake reply is not read yet
+    ack_pending: bool,
+    /// Scans dropped before their end, whose remaining pages are skipped
+    abandoned: HashSet<u32>,
+    /// Whether the connection is out of sync or closed after an error
+    broken: bool,
+}
+
+impl Client {
+    pub fn new(stream: TcpStream) -> Self {
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: BufWriter::new(stream),
+            next_id: 0,
+            greeted: false,
+            ack_pending: false,
+            abandoned: HashSet::new(),
+            broken: false,
+        }
+    }
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> ClientResult<Self> {
+        Self::connect_with(addr, ClientOptions::new())
+    }
+    /// Connect with options, a request timing out breaks the connection
+    pub fn connect_with<A: ToSocketAddrs>(addr: A, opts: ClientOptions) -> ClientResult<Self> {
+        let mut last_err = None;
+        for addr in addr.to_socket_addrs()? {
+            let stream = match opts.connect_timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match stream {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(opts.request_timeout)?;
+                    stream.set_write_timeout(opts.request_timeout)?;
+                    return Ok(Client::new(stream));
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        let err = last_err.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no address to connect")
+        });
+        Err(err.into())
+    }
+    pub fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<()> {
+        done(self.call(Command::Set { key, val })?)
+    }
+    /// Set a value which expires after `ttl`, in whole milliseconds
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> ClientResult<()> {
+        done(self.call(Command::SetWithTtl { key, val, ttl })?)
+    }
+    /// Remove value by key, returns whether the key is found
+    pub fn remove(&mut self, key: Vec<u8>) -> ClientResult<bool> {
+        found(self.call(Command::Remove { key })?)
+    }
+    pub fn get(&mut self, key: Vec<u8>) -> ClientResult<Option<Vec<u8>>> {
+        value(self.call(Command::Get { key })?)
+    }
+    /// Replace the value of `key` with `new` if it is `expected`, `None`
+    /// meaning absent on either side. Returns whether it is swapped
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> ClientResult<bool> {
+        swapped(self.call(Command::CompareAndSwap { key, expected, new })?)
+    }
+    /// Set a value only if `key` is absent, returns whether it is set
+    pub fn set_if_absent(&mut self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<bool> {
+        self.compare_and_swap(key, None, Some(val))
+    }
+    /// Apply writes of `batch` in order and atomically
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> ClientResult<()> {
+        done(self.call(Command::Batch { batch })?)
+    }
+    /// Scan keys in `[start, end)`, pairs are read from the connection lazily
+    pub fn scan(
+        &mut self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> ClientResult<Scan<'_>> {
+        let limit = limit as u64;
+        let id = self.send(Command::Scan { start, end, limit })?;
+        Ok(Scan::new(self, id))
+    }
+    /// Scan keys starting with `prefix`, pairs are read from the connection
+    /// lazily
+    pub fn scan_prefix(&mut self, prefix: Vec<u8>, limit: usize) -> ClientResult<Scan<'_>> {
+        let limit = limit as u64;
+        let id = self.send(Command::ScanPrefix { prefix, limit })?;
+        Ok(Scan::new(self, id))
+    }
+    /// Start a pipeline, whose commands are sent together without waiting for
+    /// each reply
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline::new(self)
+    }
+    /// Check that the connection is alive
+    pub fn ping(&mut self) -> ClientResult<()> {
+        done(self.call(Command::Ping)?)
+    }
+    /// Close the connection gracefully
+    pub fn close(mut self) -> ClientResult<()> {
+        done(self.call(Command::Close)?)
+    }
+    fn call(&mut self, cmd: Command) -> ClientResult<Reply> {
+        let id = self.send(cmd)?;
+        self.recv(id)
+    }
+    /// Send a request, the handshake goes along with the first one
+    fn send(&mut self, cmd: Command) -> ClientResult<u32> {
+        let sent = self.greet().and_then(|()| {
+            let id = self.alloc_id();
+            self.writer.write_all(&Request { id, cmd }.encode())?;
+            self.writer.flush()?;
+            Ok(id)
+        });
+        self.check(sent)
+    }
+    /// Mark the connection broken by errors other than a failed request
+    fn check<T>(&mut self, result: ClientResult<T>) -> ClientResult<T> {
+        if let Err(e) = &result {
+            if !e.is_request_failure() {
+                self.broken = true;
+            }
+        }
+        result
+    }
+    fn greet(&mut self) -> ClientResult<()> {
+        if !self.greeted {
+            self.writer.write_all(&hello())?;
+            self.greeted = true;
+            self.ack_pending = true;
+        }
+        Ok(())
+    }
+    fn alloc_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        id
+    }
+    /// Receive the reply of request `id`
+    fn recv(&mut self, id: u32) -> ClientResult<Reply> {
+        let reply = self.try_recv(id);
+        self.check(reply)
+    }
+    fn try_recv(&mut self, id: u32) -> ClientResult<Reply> {
+        if self.ack_pending {
+            let mut ack = [0; 5];
+            self.reader.read_exact(&mut ack)?;
+            if check_ack(&ack)? {
+                let mut len = [0; 4];
+                self.reader.read_exact(&mut len)?;
+                let mut msg = Vec::new();
+                let len = u32::from_be_bytes(len).into();
+                self.reader.by_ref().take(len).read_to_end(&mut msg)?;
+                return Err(refused(&msg));
+            }
+            self.ack_pending = false;
+        }
+        loop {
+            let frame = protocol::read_frame(&mut self.reader)?.ok_or_else(closed)?;
+            let resp = Response::decode(&frame)?;
+            debug!(
+                "response of request {} received: {:?}",
+                resp.id,
+                resp.reply.status()
+            );
+            if self.abandoned.contains(&resp.id) {
+                if !matches!(resp.reply, Reply::Pairs(_)) {
+                    self.abandoned.remove(&resp.id);
+                }
+                continue;
+            }
+            if resp.id != id {
+                Err(unknown_id(resp.id))?
+            }
+            return Ok(resp.reply);
+        }
+    }
+}
+
+/// Commands buffered to be sent at once by `execute`, of a `Client` or an
+/// `AsyncClient`. It returns their replies in order, a command failed by
+/// server gets `Reply::Error`, which does not fail the others
+pub struct Pipeline<'a, C = Client> {
+    client: &'a mut C,
+    cmds: Vec<Command>,
+}
+
+/// Pipeline of an `AsyncClient`
+pub type AsyncPipeline<'a> = Pipeline<'a, AsyncClient>;
+
+impl<'a, C> Pipeline<'a, C> {
+    fn new(client: &'a mut C) -> Self {
+        Pipeline {
+            client,
+            cmds: Vec::new(),
+        }
+    }
+    pub fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> &mut Self {
+        self.cmds.push(Command::Set { key, val });
+        self
+    }
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> &mut Self {
+        self.cmds.push(Command::SetWithTtl { key, val, ttl });
+        self
+    }
+    pub fn get(&mut self, key: Vec<u8>) -> &mut Self {
+        self.cmds.push(Command::Get { key });
+        self
+    }
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.cmds.push(Command::Remove { key });
+        self
+    }
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> &mut Self {
+        self.cmds
+            .push(Command::CompareAndSwap { key, expected, new });
+        self
+    }
+    pub fn apply_batch(&mut self, batch: WriteBatch) -> &mut Self {
+        self.cmds.push(Command::Batch { batch });
+        self
+    }
+}
+
+impl Pipeline<'_, Client> {
+    pub fn execute(&mut self) -> ClientResult<Vec<Reply>> {
+        let cmds = mem::take(&mut self.cmds);
+        if cmds.is_empty() {
+            return Ok(Vec::new());
+        }
+        let client = &mut *self.client;
+        let greeted = client.greet().and_then(|()| Ok(client.writer.flush()?));
+        client.check(greeted)?;
+        let reqs = cmds
+            .into_iter()
+            .map(|cmd| Request {
+                id: client.alloc_id(),
+                cmd,
+            })
+            .collect::<Vec<_>>();
+        let ids = reqs.iter().map(|req| req.id).collect::<Vec<_>>();
+        let stream = client.writer.get_ref().try_clone().map_err(Into::into);
+        let stream = client.check(stream)?;
+        // requests are written by another thread while replies are read, so
+        // that neither side blocks on a full socket buffer
+        let result = thread::scope(|s| {
+            let sender = s.spawn(move || -> ClientResult<()> {
+                let mut writer = BufWriter::new(stream);
+                for req in reqs {
+                    writer.write_all(&req.encode())?;
+                }
+                writer.flush()?;
+                Ok(())
+            });
+            let replies = ids
+                .into_iter()
+                .map(|id| client.recv(id))
+                .collect::<ClientResult<Vec<_>>>();
+            let sent = sender.join().unwrap_or_else(|e| panic::resume_unwind(e));
+            sent.and(replies)
+        });
+        client.check(result)
+    }
+}
+
+/// Handshake starting a session
+fn hello() -> [u8; 5] {
+    let mut hello = [protocol::VERSION; 5];
+    hello[..4].copy_from_slice(&protocol::HANDSHAKE);
+    hello
+}
+
+/// Check the answer to the handshake, returns whether the server refused the
+/// session, then a message follows
+fn check_ack(ack: &[u8; 5]) -> ClientResult<bool> {
+    if ack[..4] == protocol::HANDSHAKE && ack[4] == protocol::VERSION_REFUSED {
+        return Ok(true);
+    }
+    if *ack != hello() {
+        Err(ClientError::Protocol(format!(
+            "unexpected handshake {:?}",
+            ack
+        )))?
+    }
+    Ok(false)
+}
+
+/// Error of a session refused with the message `msg`
+fn refused(msg: &[u8]) -> ClientError {
+    ClientError::Server(
+        Status::Unavailable,
+        String::from_utf8_lossy(msg).into_owned(),
+    )
+}
+
+/// Reply of a command without result
+fn done(reply: Reply) -> ClientResult<()> {
+    match reply {
+        Reply::Done => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Reply of a remove, whether the key is found
+fn found(reply: Reply) -> ClientResult<bool> {
+    match reply {
+        Reply::Done => Ok(true),
+        Reply::NotFound => Ok(false),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Reply of a compare and swap, whether it is swapped
+fn swapped(reply: Reply) -> ClientResult<bool> {
+    match reply {
+        Reply::Done => Ok(true),
+        Reply::Conflict => Ok(false),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Reply of a get
+fn value(reply: Reply) -> ClientResult<Option<Vec<u8>>> {
+    match reply {
+        Reply::Value(val) => Ok(Some(val)),
+        Reply::NotFound => Ok(None),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// A reply streaming a scan, `None` once the scan is done
+fn scan_page(reply: Reply) -> ClientResult<Option<Pairs>> {
+    match reply {
+        Reply::Pairs(pairs) => Ok(Some(pairs)),
+        Reply::Done => Ok(None),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+/// Turn an unexpected reply into an error
+fn unexpected(reply: Reply) -> ClientError {
+    match reply {
+        Reply::Error(status, msg) => ClientError::Server(status, msg),
+        reply => ClientError::Protocol(format!("unexpected reply {:?}", reply.status())),
+    }
+}
+
+fn unknown_id(id: u32) -> ClientError {
+    ClientError::Protocol(format!("response to unknown request {}", id))
+}
+
+/// Iterator over pairs streamed by a scan
+pub struct Scan<'a> {
+    client: &'a mut Client,
+    id: u32,
+    page: VecDeque<(Vec<u8>, Vec<u8>)>,
+    done: bool,
+}
+
+impl<'a> Scan<'a> {
+    fn new(client: &'a mut Client, id: u32) -> Self {
+        Scan {
+            client,
+            id,
+            page: VecDeque::new(),
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = ClientResult<(Vec<u8>, Vec<u8>)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.page.is_empty() {
+            if self.done {
+                return None;
+            }
+            match self.client.recv(self.id).and_then(scan_page) {
+                Ok(Some(pairs)) => self.page.extend(pairs),
+                Ok(None) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.page.pop_front().map(Ok)
+    }
+}
+
+impl Drop for Scan<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.client.abandoned.insert(self.id);
+        }
+    }
+}
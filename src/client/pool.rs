@@ -0,0 +1,422 @@
+//! Pools of connections to a server, `ClientPool` of `Client` and
+//! `AsyncClientPool` of `AsyncClient`.
+//!
+//! A connection returns to its pool when its guard is dropped, unless an error
+//! broke it. Connections idle for too long are closed whenever one is taken or
+//! returned, and those idle for a while are pinged before reuse. `get` and
+//! `set` are idempotent, so they are retried with exponential backoff on a new
+//! connection if the transport fails. `remove` is never retried, since a retry
+//! can not tell whether the first attempt removed it.
+use super::{AsyncClient, Client, ClientError, ClientOptions, ClientResult};
+use crate::protocol::Status;
+use std::future::Future;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::debug;
+
+const MAX_SIZE: usize = 16;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const PING_INTERVAL: Duration = Duration::from_secs(10);
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+const RETRIES: u32 = 3;
+const BACKOFF: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    max_size: usize,
+    idle_timeout: Duration,
+    ping_interval: Duration,
+    checkout_timeout: Duration,
+    retries: u32,
+    backoff: Duration,
+    client: ClientOptions,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: MAX_SIZE,
+            idle_timeout: IDLE_TIMEOUT,
+            ping_interval: PING_INTERVAL,
+            checkout_timeout: CHECKOUT_TIMEOUT,
+            retries: RETRIES,
+            backoff: BACKOFF,
+            client: ClientOptions::new(),
+        }
+    }
+}
+
+impl PoolOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Most connections open at a time, idle or in use
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size.max(1);
+        self
+    }
+    /// Close connections idle for this long, checked whenever a connection is
+    /// taken or returned
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Ping connections idle for this long before reuse
+    pub fn ping_interval(mut self, interval: Duration) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+    /// Longest wait for a connection when all of them are in use
+    pub fn checkout_timeout(mut self, timeout: Duration) -> Self {
+        self.checkout_timeout = timeout;
+        self
+    }
+    /// Retries of an idempotent operation, the first one after `backoff`,
+    /// each later one after twice the previous delay
+    pub fn retry(mut self, retries: u32, backoff: Duration) -> Self {
+        self.retries = retries;
+        self.backoff = backoff;
+        self
+    }
+    pub fn client_options(mut self, opts: ClientOptions) -> Self {
+        self.client = opts;
+        self
+    }
+}
+
+struct Idle<C> {
+    client: C,
+    since: Instant,
+}
+
+struct PoolState<C> {
+    /// The most recently used is the last
+    idle: Vec<Idle<C>>,
+    /// Connections open, idle or in use
+    open: usize,
+}
+
+impl<C> PoolState<C> {
+    fn new() -> Self {
+        PoolState {
+            idle: Vec::new(),
+            open: 0,
+        }
+    }
+    /// Close idle connections expired
+    fn evict(&mut self, timeout: Duration) {
+        let before = self.idle.len();
+        self.idle.retain(|idle| idle.since.elapsed() < timeout);
+        self.open -= before - self.idle.len();
+    }
+    /// Keep a connection returned for reuse
+    fn put_back(&mut self, client: C, timeout: Duration) {
+        self.idle.push(Idle {
+            client,
+            since: Instant::now(),
+        });
+        self.evict(timeout);
+    }
+}
+
+/// Failures that may pass on a new connection
+fn retryable(e: &ClientError) -> bool {
+    matches!(
+        e,
+        ClientError::Transport(_)
+            | ClientError::Timeout
+            | ClientError::Server(Status::Unavailable, _)
+    )
+}
+
+/// Pool of blocking clients, shared by threads
+pub struct ClientPool {
+    addr: String,
+    opts: PoolOptions,
+    state: Mutex<PoolState<Client>>,
+    available: Condvar,
+}
+
+impl ClientPool {
+    pub fn new(addr: &str, opts: PoolOptions) -> Self {
+        ClientPool {
+            addr: addr.to_owned(),
+            opts,
+            state: Mutex::new(PoolState::new()),
+            available: Condvar::new(),
+        }
+    }
+    pub fn set(&self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<()> {
+        self.retry(|client| client.set(key.clone(), val.clone()))
+    }
+    pub fn get(&self, key: Vec<u8>) -> ClientResult<Option<Vec<u8>>> {
+        self.retry(|client| client.get(key.clone()))
+    }
+    pub fn remove(&self, key: Vec<u8>) -> ClientResult<bool> {
+        self.checkout()?.remove(key)
+    }
+    /// Connections open, idle or in use
+    pub fn size(&self) -> usize {
+        self.lock().open
+    }
+    /// Take a connection, which returns to the pool when dropped
+    pub fn checkout(&self) -> ClientResult<PooledClient<'_>> {
+        let deadline = Instant::now() + self.opts.checkout_timeout;
+        let mut state = self.lock();
+        loop {
+            state.evict(self.opts.idle_timeout);
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                let mut client = idle.client;
+                if idle.since.elapsed() < self.opts.ping_interval || client.ping().is_ok() {
+                    return Ok(self.guard(client));
+                }
+                debug!("dropping dead connection to {}", self.addr);
+                state = self.lock();
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.opts.max_size {
+                state.open += 1;
+                drop(state);
+                return match Client::connect_with(self.addr.as_str(), self.opts.client.clone()) {
+                    Ok(client) => Ok(self.guard(client)),
+                    Err(e) => {
+                        self.release();
+                        Err(e)
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(ClientError::Timeout);
+            }
+            state = self
+                .available
+                .wait_timeout(state, deadline - now)
+                .expect("pool lock poisoned")
+                .0;
+        }
+    }
+    fn retry<T>(&self, mut op: impl FnMut(&mut Client) -> ClientResult<T>) -> ClientResult<T> {
+        let mut backoff = self.opts.backoff;
+        let mut attempts = 0;
+        loop {
+            match self.checkout().and_then(|mut client| op(&mut client)) {
+                Err(e) if attempts < self.opts.retries && retryable(&e) => {
+                    debug!("retrying in {:?} after error: {}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+    fn guard(&self, client: Client) -> PooledClient<'_> {
+        PooledClient {
+            pool: self,
+            client: Some(client),
+        }
+    }
+    /// Forget a connection closed
+    fn release(&self) {
+        self.lock().open -= 1;
+        self.available.notify_one();
+    }
+    fn lock(&self) -> MutexGuard<'_, PoolState<Client>> {
+        self.state.lock().expect("pool lock poisoned")
+    }
+}
+
+/// Connection taken from a `ClientPool`
+pub struct PooledClient<'a> {
+    pool: &'a ClientPool,
+    client: Option<Client>,
+}
+
+impl Deref for PooledClient<'_> {
+    type Target = Client;
+    fn deref(&self) -> &Client {
+        self.client.as_ref().expect("present until dropped")
+    }
+}
+
+impl DerefMut for PooledClient<'_> {
+    fn deref_mut(&mut self) -> &mut Client {
+        self.client.as_mut().expect("present until dropped")
+    }
+}
+
+impl Drop for PooledClient<'_> {
+    fn drop(&mut self) {
+        let client = self.client.take().expect("present until dropped");
+        if client.broken {
+            self.pool.release();
+            return;
+        }
+        let timeout = self.pool.opts.idle_timeout;
+        self.pool.lock().put_back(client, timeout);
+        self.pool.available.notify_one();
+    }
+}
+
+/// Pool of async clients, shared by tasks
+pub struct AsyncClientPool {
+    addr: String,
+    opts: PoolOptions,
+    state: Mutex<PoolState<AsyncClient>>,
+    /// A connection in use holds a permit, an idle one does not. New
+    /// connections are opened only if none is idle, so that at most `max_size`
+    /// are open
+    permits: Arc<Semaphore>,
+}
+
+impl AsyncClientPool {
+    pub fn new(addr: &str, opts: PoolOptions) -> Self {
+        AsyncClientPool {
+            addr: addr.to_owned(),
+            permits: Arc::new(Semaphore::new(opts.max_size)),
+            opts,
+            state: Mutex::new(PoolState::new()),
+        }
+    }
+    pub async fn set(&self, key: Vec<u8>, val: Vec<u8>) -> ClientResult<()> {
+        self.retry(|mut client| {
+            let (key, val) = (key.clone(), val.clone());
+            async move { client.set(key, val).await }
+        })
+        .await
+    }
+    pub async fn get(&self, key: Vec<u8>) -> ClientResult<Option<Vec<u8>>> {
+        self.retry(|mut client| {
+            let key = key.clone();
+            async move { client.get(key).await }
+        })
+        .await
+    }
+    pub async fn remove(&self, key: Vec<u8>) -> ClientResult<bool> {
+        self.checkout().await?.remove(key).await
+    }
+    /// Connections open, idle or in use
+    pub fn size(&self) -> usize {
+        self.lock().open
+    }
+    /// Take a connection, which returns to the pool when dropped
+    pub async fn checkout(&self) -> ClientResult<PooledAsyncClient<'_>> {
+        let permit = tokio::time::timeout(
+            self.opts.checkout_timeout,
+            self.permits.clone().acquire_owned(),
+        )
+        .await
+        .map_err(|_| ClientError::Timeout)?
+        .expect("semaphore never closed");
+        loop {
+            let idle = {
+                let mut state = self.lock();
+                state.evict(self.opts.idle_timeout);
+                let idle = state.idle.pop();
+                if idle.is_none() {
+                    state.open += 1;
+                }
+                idle
+            };
+            // the connection is forgotten if checking it fails or is cancelled
+            let slot = OpenSlot { pool: self };
+            let client = match idle {
+                Some(idle) => {
+                    let mut client = idle.client;
+                    if idle.since.elapsed() >= self.opts.ping_interval
+                        && client.ping().await.is_err()
+                    {
+                        debug!("dropping dead connection to {}", self.addr);
+                        continue;
+                    }
+                    client
+                }
+                None => {
+                    AsyncClient::connect_with(self.addr.as_str(), self.opts.client.clone()).await?
+                }
+            };
+            mem::forget(slot);
+            return Ok(PooledAsyncClient {
+                pool: self,
+                client: Some(client),
+                _permit: permit,
+            });
+        }
+    }
+    async fn retry<'a, T, F, Fut>(&'a self, mut op: F) -> ClientResult<T>
+    where
+        F: FnMut(PooledAsyncClient<'a>) -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        let mut backoff = self.opts.backoff;
+        let mut attempts = 0;
+        loop {
+            let result = match self.checkout().await {
+                Ok(client) => op(client).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Err(e) if attempts < self.opts.retries && retryable(&e) => {
+                    debug!("retrying in {:?} after error: {}", backoff, e);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                    attempts += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, PoolState<AsyncClient>> {
+        self.state.lock().expect("pool lock poisoned")
+    }
+}
+
+/// Counted open connection, released when dropped
+struct OpenSlot<'a> {
+    pool: &'a AsyncClientPool,
+}
+
+impl Drop for OpenSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.lock().open -= 1;
+    }
+}
+
+/// Connection taken from an `AsyncClientPool`
+pub struct PooledAsyncClient<'a> {
+    pool: &'a AsyncClientPool,
+    client: Option<AsyncClient>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledAsyncClient<'_> {
+    type Target = AsyncClient;
+    fn deref(&self) -> &AsyncClient {
+        self.client.as_ref().expect("present until dropped")
+    }
+}
+
+impl DerefMut for PooledAsyncClient<'_> {
+    fn deref_mut(&mut self) -> &mut AsyncClient {
+        self.client.as_mut().expect("present until dropped")
+    }
+}
+
+impl Drop for PooledAsyncClient<'_> {
+    fn drop(&mut self) {
+        let client = self.client.take().expect("present until dropped");
+        let mut state = self.pool.lock();
+        if client.is_broken() {
+            state.open -= 1;
+            return;
+        }
+        state.put_back(client, self.pool.opts.idle_timeout);
+    }
+}
@@ -0,0 +1,49 @@
+/// A write of a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    /// Removing an absent key is not an error in a batch
+    Remove(Vec<u8>),
+}
+
+/// Writes applied in order by `KvsEngine::apply_batch`, all or none of them
+/// survive a crash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn set(&mut self, key: Vec<u8>, val: Vec<u8>) {
+        self.ops.push(BatchOp::Set(key, val));
+    }
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove(key));
+    }
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> Self {
+        WriteBatch { ops }
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
@@ -0,0 +1,84 @@
+//! Hint files list the records of a compacted segment without their values,
+//! so the index can be rebuilt without reading the segment itself.
+//!
+//! Hint file:
+//! | hint | hint | ... | crc: u32 |
+//! Hint:
+//! | flags: u8 | key_len: u32 | offset: u64 | len: u32 | expiry | key |
+//! Integers are big-endian, `crc` is the CRC32 of all hints. `expiry` is the
+//! u64 expiry of the record, present only if `FLAG_EXPIRES` is set.
+use crate::{MyErr, Result};
+
+const HINT_HEADER_LEN: usize = 17;
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_EXPIRES: u8 = 2;
+const EXPIRY_LEN: usize = 8;
+
+pub struct Hint {
+    pub key: Vec<u8>,
+    pub offset: u64,
+    pub len: u32,
+    pub is_del: bool,
+    pub expires_at: Option<u64>,
+}
+
+pub fn encode_hints(hints: &[Hint]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for h in hints {
+        let mut flags = if h.is_del { FLAG_TOMBSTONE } else { 0 };
+        if h.expires_at.is_some() {
+            flags |= FLAG_EXPIRES;
+        }
+        buf.push(flags);
+        buf.extend_from_slice(&(h.key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&h.offset.to_be_bytes());
+        buf.extend_from_slice(&h.len.to_be_bytes());
+        if let Some(expires_at) = h.expires_at {
+            buf.extend_from_slice(&expires_at.to_be_bytes());
+        }
+        buf.extend_from_slice(&h.key);
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_be_bytes());
+    buf
+}
+
+pub fn decode_hints(buf: &[u8]) -> Result<Vec<Hint>> {
+    if buf.len() < 4 {
+        Err(MyErr::InvalidRecord)?
+    }
+    let (mut body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body).to_be_bytes() != crc {
+        Err(MyErr::InvalidRecord)?
+    }
+    let mut hints = Vec::new();
+    while !body.is_empty() {
+        if body.len() < HINT_HEADER_LEN {
+            Err(MyErr::InvalidRecord)?
+        }
+        let (head, rest) = body.split_at(HINT_HEADER_LEN);
+        let klen = u32::from_be_bytes(head[1..5].try_into()?) as usize;
+        let (expires_at, rest) = if head[0] & FLAG_EXPIRES != 0 {
+            if rest.len() < EXPIRY_LEN {
+                Err(MyErr::InvalidRecord)?
+            }
+            let (expiry, rest) = rest.split_at(EXPIRY_LEN);
+            (Some(u64::from_be_bytes(expiry.try_into()?)), rest)
+        } else {
+            (None, rest)
+        };
+        if rest.len() < klen {
+            Err(MyErr::InvalidRecord)?
+        }
+        let (key, rest) = rest.split_at(klen);
+        hints.push(Hint {
+            key: key.to_vec(),
+            offset: u64::from_be_bytes(head[5..13].try_into()?),
+            len: u32::from_be_bytes(head[13..17].try_into()?),
+            is_del: head[0] & FLAG_TOMBSTONE != 0,
+            expires_at,
+        });
+        body = rest;
+    }
+    Ok(hints)
+}
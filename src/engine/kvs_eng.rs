@@ -1,16 +1,23 @@
-use crate::{thread_pool::ThreadPool, KvsEngine, MyErr, Result};
+use super::hint::{decode_hints, encode_hints, Hint};
+use super::manifest::{Manifest, MANIFEST_TMP};
+use super::pread::pread_exact;
+use super::record::{self, Entry};
+use super::{expires_at, now_millis};
+use crate::{thread_pool::ThreadPool, BatchOp, KvsEngine, MyErr, Result, Transaction, WriteBatch};
 
 use std::{
     clone::Clone,
-    collections::BTreeMap,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap},
     fmt,
-    fs::{read_dir, remove_file, rename, File},
-    io::{self, Read, Seek, SeekFrom, Write},
-    os::windows::fs::FileExt,
-    path::PathBuf,
+    fs::{self, read_dir, remove_file, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    mem,
+    ops::Bound,
+    path::{Path, PathBuf},
     result,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicU32, Ordering},
         Arc, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
@@ -20,25 +27,47 @@ use std::{
 use async_trait::async_trait;
 use crossbeam::{channel, select};
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
-use serde_json;
+use failure::err_msg;
 use tokio::sync::oneshot;
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
-const SEGMENT_SIZE: u64 = 1 * 1024;
+const SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
 const COMPACT_THRESHOLD: u64 = 2 * SEGMENT_SIZE;
-const COMPACT_CHECK: u64 = 1;
+const COMPACT_CHECK: Duration = Duration::from_secs(1);
+/// Segments with at least this ratio of stale bytes are compacted
+const COMPACT_RATIO: f64 = 0.5;
+/// Compaction output of older releases
+const COMPACTING: &str = "compacting";
+const COMPACTING_HINT: &str = "compacting.hint";
+/// Attempts of a transaction before it fails with `MyErr::Conflict`
+const TX_ATTEMPTS: u32 = 10;
+/// Delay before the first retry of a conflicted transaction, doubled before
+/// each later one
+const TX_BACKOFF: Duration = Duration::from_millis(1);
 
 #[derive(Debug, Clone)]
 pub struct Index {
     file: u32,
     len: u32,
     offset: u64,
+    expires_at: Option<u64>,
+    /// Bumped by every write of the key, transactions are validated with it.
+    /// Keys loaded on open are at version 0.
+    version: u64,
 }
 
 impl Index {
-    fn new(file: u32, len: u32, offset: u64) -> Self {
-        Index { file, len, offset }
+    fn new(file: u32, len: u32, offset: u64, expires_at: Option<u64>) -> Self {
+        Index {
+            file,
+            len,
+            offset,
+            expires_at,
+            version: 0,
+        }
+    }
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
     }
 }
 
@@ -52,11 +81,70 @@ impl fmt::Display for Index {
     }
 }
 
+/// When to fsync the active segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync before acknowledging a write. Writers queued on the same lock
+    /// are committed as a group and share one sync.
+    Always,
+    /// Sync in background periodically
+    Interval(Duration),
+    /// Never sync explicitly, leave it to the operating system
+    Never,
+}
+
+/// Options to open a `KvStore`
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    segment_size: u64,
+    compact_threshold: u64,
+    compact_check: Duration,
+    sync: SyncPolicy,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            segment_size: SEGMENT_SIZE,
+            compact_threshold: COMPACT_THRESHOLD,
+            compact_check: COMPACT_CHECK,
+            sync: SyncPolicy::Always,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Size in bytes at which the active segment is cut
+    pub fn segment_size(mut self, size: u64) -> Self {
+        self.segment_size = size;
+        self
+    }
+    /// Bytes of stale records that trigger a compaction
+    pub fn compact_threshold(mut self, threshold: u64) -> Self {
+        self.compact_threshold = threshold;
+        self
+    }
+    /// How often the compactor checks the threshold
+    pub fn compact_check(mut self, interval: Duration) -> Self {
+        self.compact_check = interval;
+        self
+    }
+    pub fn sync(mut self, sync: SyncPolicy) -> Self {
+        self.sync = sync;
+        self
+    }
+}
+
 pub struct KvStore<ThreadPool> {
     dir_path: PathBuf,
     reader: Reader,
     writer: Arc<Mutex<Writer>>,
-    compactor: Option<CompactorHandle>,
+    pending: Arc<Mutex<Vec<PendingWrite>>>,
+    compactor: Option<WorkerHandle>,
+    syncer: Option<WorkerHandle>,
     tp: Arc<Mutex<ThreadPool>>,
 }
 
@@ -66,52 +154,215 @@ impl<P: ThreadPool> Clone for KvStore<P> {
             dir_path: self.dir_path.clone(),
             reader: self.reader.clone(),
             writer: self.writer.clone(),
-            // only the KvStore in main loop hold compactor handle
+            pending: self.pending.clone(),
+            // only the KvStore in main loop hold background worker handles
             compactor: None,
+            syncer: None,
             tp: self.tp.clone(),
         }
     }
 }
 
+enum WriteOp {
+    /// Key, value and its expiry
+    Set(Vec<u8>, Vec<u8>, Option<u64>),
+    Remove(Vec<u8>),
+    /// Key, expected and new value
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    Batch(WriteBatch),
+    /// Versions of keys read, `None` if absent, and the writes to commit
+    Transaction(Vec<(Vec<u8>, Option<u64>)>, WriteBatch),
+}
+
+/// A write and the sender of whether it is applied
+type PendingWrite = (WriteOp, oneshot::Sender<Result<bool>>);
+
 struct Writer {
     dir: PathBuf,
     file_id: u32,
     file: File,
-    indices: Arc<DashMap<String, Index>>,
+    indices: Arc<DashMap<Vec<u8>, Index>>,
+    keys: Arc<RwLock<BTreeSet<Vec<u8>>>>,
     reader: Arc<RwLock<BTreeMap<u32, File>>>,
-    uncompacted: Arc<AtomicU64>,
+    stale: Arc<DashMap<u32, u64>>,
+    next_id: Arc<AtomicU32>,
+    manifest: Arc<Mutex<Manifest>>,
+    segment_size: u64,
+    sync: SyncPolicy,
+    /// Last version given to a write
+    version: u64,
 }
 
 impl Writer {
-    fn set(&mut self, key: String, val: String) -> Result<()> {
-        let (len, offset) = append_entry(&mut self.file, Entry::put(key.clone(), val))?;
-        let idx = Index::new(self.file_id, len, offset);
-        if let Some(old) = self.indices.insert(key, idx) {
-            self.uncompacted
-                .fetch_add(4 + old.len as u64, Ordering::Relaxed);
-        }
-        if offset + len as u64 >= SEGMENT_SIZE {
-            if let Err(err) = self.cut() {
-                error!("failed to cut {}", err);
+    /// Apply a group of writes, then sync once if needed before replying
+    fn commit(&mut self, batch: Vec<PendingWrite>) {
+        let mut results = Vec::with_capacity(batch.len());
+        for (op, sdr) in batch {
+            let rlt = match op {
+                WriteOp::Set(key, val, expiry) => self.set(key, val, expiry).map(|()| true),
+                WriteOp::Remove(key) => self.remove(key).map(|()| true),
+                WriteOp::CompareAndSwap(key, expected, new) => {
+                    self.compare_and_swap(key, expected, new)
+                }
+                WriteOp::Batch(batch) => self.apply_batch(batch).map(|()| true),
+                WriteOp::Transaction(reads, batch) => self.commit_transaction(reads, batch),
+            };
+            results.push((rlt, sdr));
+        }
+        if self.sync == SyncPolicy::Always {
+            if let Err(err) = self.file.sync_data() {
+                error!("failed to sync segment {}: {}", self.file_id, err);
+                for (rlt, _) in results.iter_mut().filter(|(rlt, _)| rlt.is_ok()) {
+                    *rlt = Err(err_msg(format!("failed to sync: {}", err)));
+                }
             }
         }
+        trace!("committed {} writes", results.len());
+        for (rlt, sdr) in results {
+            if sdr.send(rlt).is_err() {
+                debug!("write result dropped by receiver");
+            }
+        }
+    }
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>, expiry: Option<u64>) -> Result<()> {
+        let ent = Entry::put(key.clone(), val, expiry);
+        let (len, offset) = append_entry(&mut self.file, ent)?;
+        self.index_put(key, Index::new(self.file_id, len, offset, expiry));
+        self.cut_if_full(offset + len as u64);
         Ok(())
     }
-    fn remove(&mut self, key: String) -> Result<()> {
-        let (_, old) = self.indices.remove(&key).ok_or(MyErr::KeyNotFound)?;
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        // an expired key is already gone, `expire` drops its index
+        let now = now_millis();
+        let (_, old) = self
+            .indices
+            .remove_if(&key, |_, idx| !idx.is_expired(now))
+            .ok_or(MyErr::KeyNotFound)?;
+        self.keys.write().unwrap().remove(&key);
         let (len, offset) = append_entry(&mut self.file, Entry::del(key))?;
-        let uncmpct = 4 + old.len as u64 + 4 + len as u64;
-        self.uncompacted.fetch_add(uncmpct, Ordering::Relaxed);
-        if offset + len as u64 >= SEGMENT_SIZE {
+        add_stale(&self.stale, old.file, old.len);
+        add_stale(&self.stale, self.file_id, len);
+        self.cut_if_full(offset + len as u64);
+        Ok(())
+    }
+    /// Append writes of `batch` as one group record, then index them in order
+    fn apply_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ents: Vec<Entry> = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, val) => Entry::put(key, val, None),
+                BatchOp::Remove(key) => Entry::del(key),
+            })
+            .collect();
+        let (group, members) = record::encode_group(&ents);
+        let offset = append_entry_bytes(&mut self.file, &group)?;
+        for (ent, (pos, len)) in ents.into_iter().zip(members) {
+            let len = len as u32;
+            if ent.is_del {
+                if let Some((_, old)) = self.indices.remove(&ent.key) {
+                    self.keys.write().unwrap().remove(&ent.key);
+                    add_stale(&self.stale, old.file, old.len);
+                }
+                add_stale(&self.stale, self.file_id, len);
+            } else {
+                let idx = Index::new(self.file_id, len, offset + pos as u64, None);
+                self.index_put(ent.key, idx);
+            }
+        }
+        self.cut_if_full(offset + group.len() as u64);
+        Ok(())
+    }
+    /// Apply `batch` only if keys of `reads` are still at their versions,
+    /// returns whether it is applied
+    fn commit_transaction(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<u64>)>,
+        batch: WriteBatch,
+    ) -> Result<bool> {
+        let now = now_millis();
+        let changed = reads.into_iter().any(|(key, version)| {
+            let current = self.indices.get(&key).filter(|idx| !idx.is_expired(now));
+            current.map(|idx| idx.version) != version
+        });
+        if changed {
+            return Ok(false);
+        }
+        self.apply_batch(batch)?;
+        Ok(true)
+    }
+    fn index_put(&mut self, key: Vec<u8>, mut idx: Index) {
+        self.version += 1;
+        idx.version = self.version;
+        match self.indices.insert(key.clone(), idx) {
+            Some(old) => add_stale(&self.stale, old.file, old.len),
+            None => {
+                self.keys.write().unwrap().insert(key);
+            }
+        }
+    }
+    /// Cut the active segment if it reaches the segment size at `end`
+    fn cut_if_full(&mut self, end: u64) {
+        if end >= self.segment_size {
             if let Err(err) = self.cut() {
                 error!("failed to cut {}", err);
             }
         }
-        Ok(())
+    }
+    /// Writes are serialized by the writer lock, so nothing changes `key`
+    /// between the comparison and the swap
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let current = read_value(&self.reader, &self.indices, &key)?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(val) => self.set(key, val, None)?,
+            None if current.is_some() => match self.remove(key) {
+                // expired since read, absent anyway
+                Err(e) if matches!(e.downcast_ref(), Some(MyErr::KeyNotFound)) => {}
+                rlt => rlt?,
+            },
+            None => {}
+        }
+        Ok(true)
+    }
+    /// Drop `keys` from the index if they are expired at `now`, their records
+    /// become stale. No tombstone is needed, an expired record is dropped on
+    /// replay anyway.
+    fn expire(&mut self, keys: Vec<Vec<u8>>, now: u64) {
+        let mut expired = 0;
+        for key in keys {
+            let removed = self.indices.remove_if(&key, |_, idx| idx.is_expired(now));
+            if let Some((_, old)) = removed {
+                self.keys.write().unwrap().remove(&key);
+                add_stale(&self.stale, old.file, old.len);
+                expired += 1;
+            }
+        }
+        if expired > 0 {
+            debug!("{} expired keys dropped", expired);
+        }
     }
     fn cut(&mut self) -> Result<()> {
-        self.file_id += 1;
-        self.file = new_active_file(&self.dir, self.file_id)?;
+        if self.sync != SyncPolicy::Never {
+            self.file.sync_data()?;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let file = new_active_file(&self.dir, id)?;
+        if let Err(err) = self.manifest.lock().unwrap().add(id) {
+            remove_file(kvs_path(&self.dir, id))?;
+            return Err(err);
+        }
+        self.file_id = id;
+        self.file = file;
         let mut reader = self.reader.write().unwrap();
         reader.insert(self.file_id, self.file.try_clone()?);
         Ok(())
@@ -119,41 +370,173 @@ impl Writer {
 }
 
 // Lock order: handles -> indices (if both of them needed)
+// `keys` is never locked together with others
 #[derive(Clone)]
 struct Reader {
     handles: Arc<RwLock<BTreeMap<u32, File>>>, // todo: lock-free
-    indices: Arc<DashMap<String, Index>>,
+    indices: Arc<DashMap<Vec<u8>, Index>>,
+    keys: Arc<RwLock<BTreeSet<Vec<u8>>>>,
 }
 
 impl Reader {
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let (file, len, offset) = {
-            let handles = self.handles.read().unwrap();
-            if let Some(idx) = self.indices.get(&key) {
-                (
-                    handles.get(&idx.file).unwrap().try_clone().unwrap(),
-                    idx.len,
-                    idx.offset,
-                )
-            } else {
-                return Ok(None);
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        read_value(&self.handles, &self.indices, &key)
+    }
+    /// Read the live value of `key` with its version
+    fn get_versioned(&self, key: &[u8]) -> Result<Option<(u64, Vec<u8>)>> {
+        read_versioned(&self.handles, &self.indices, key)
+    }
+    /// Read up to `limit` live pairs in key order, starting from `lower` and
+    /// stopping at `upper` or the first key failing `cond`
+    fn scan<F>(
+        &self,
+        mut lower: Bound<Vec<u8>>,
+        upper: Bound<Vec<u8>>,
+        cond: F,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let mut pairs = Vec::new();
+        while pairs.len() < limit {
+            let batch: Vec<Vec<u8>> = self
+                .keys
+                .read()
+                .unwrap()
+                .range((lower.clone(), upper.clone()))
+                .take_while(|key| cond(key))
+                .take(limit - pairs.len())
+                .cloned()
+                .collect();
+            match batch.last() {
+                Some(last) => lower = Bound::Excluded(last.clone()),
+                None => break,
             }
-        };
-        // Read disk witout lock, trade consistency for performance
-        let mut bytes = vec![0; len as usize];
-        pread_exact(&file, &mut bytes, offset)?;
-        let ent: Entry = serde_json::from_slice(&bytes)?;
-        Ok(Some(ent.val))
+            for key in batch {
+                // skip keys removed after listed
+                if let Some(val) = self.get(key.clone())? {
+                    pairs.push((key, val));
+                }
+            }
+        }
+        Ok(pairs)
     }
 }
 
-struct CompactorHandle {
+/// Read the live value of `key`
+fn read_value(
+    handles: &RwLock<BTreeMap<u32, File>>,
+    indices: &DashMap<Vec<u8>, Index>,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    Ok(read_versioned(handles, indices, key)?.map(|(_, val)| val))
+}
+
+/// Read the live value of `key` with the version of its index
+fn read_versioned(
+    handles: &RwLock<BTreeMap<u32, File>>,
+    indices: &DashMap<Vec<u8>, Index>,
+    key: &[u8],
+) -> Result<Option<(u64, Vec<u8>)>> {
+    let (id, file, len, offset, version) = {
+        let handles = handles.read().unwrap();
+        match indices.get(key) {
+            Some(idx) if !idx.is_expired(now_millis()) => (
+                idx.file,
+                handles.get(&idx.file).unwrap().try_clone().unwrap(),
+                idx.len,
+                idx.offset,
+                idx.version,
+            ),
+            _ => return Ok(None),
+        }
+    };
+    // Read disk witout lock, trade consistency for performance
+    let mut bytes = vec![0; len as usize];
+    pread_exact(&file, &mut bytes, offset)?;
+    let ent = Entry::decode(&bytes).map_err(|_| MyErr::Corrupted(id, offset))?;
+    Ok(Some((version, ent.val)))
+}
+
+/// Transaction on `KvStore`. Keys read are recorded with their versions and
+/// writes are buffered, both are handed to the writer on commit.
+struct KvsTransaction<'a> {
+    reader: &'a Reader,
+    /// Versions and values of keys read, `None` if absent
+    reads: HashMap<Vec<u8>, Option<(u64, Vec<u8>)>>,
+    /// Values written so far, `None` if removed
+    writes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    batch: WriteBatch,
+}
+
+impl<'a> KvsTransaction<'a> {
+    fn new(reader: &'a Reader) -> Self {
+        KvsTransaction {
+            reader,
+            reads: HashMap::new(),
+            writes: HashMap::new(),
+            batch: WriteBatch::new(),
+        }
+    }
+    /// The operation committing it
+    fn into_write(self) -> WriteOp {
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, read)| (key, read.map(|(version, _)| version)))
+            .collect();
+        WriteOp::Transaction(reads, self.batch)
+    }
+    /// The operation only checking its reads, when it is aborted
+    fn into_check(mut self) -> WriteOp {
+        self.batch = WriteBatch::new();
+        self.into_write()
+    }
+}
+
+impl Transaction for KvsTransaction<'_> {
+    /// A key is read once, later reads are repeatable
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(val) = self.writes.get(&key) {
+            return Ok(val.clone());
+        }
+        if let Some(read) = self.reads.get(&key) {
+            return Ok(read.as_ref().map(|(_, val)| val.clone()));
+        }
+        let read = self.reader.get_versioned(&key)?;
+        let val = read.as_ref().map(|(_, val)| val.clone());
+        self.reads.insert(key, read);
+        Ok(val)
+    }
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.writes.insert(key.clone(), Some(val.clone()));
+        self.batch.set(key, val);
+        Ok(())
+    }
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        self.writes.insert(key.clone(), None);
+        self.batch.remove(key);
+        Ok(())
+    }
+}
+
+struct WorkerHandle {
     handle: JoinHandle<()>,
     sender: channel::Sender<()>,
 }
 
+impl WorkerHandle {
+    fn stop(self) {
+        self.sender
+            .send(())
+            .expect("failed to notify worker to exit");
+        self.handle.join().expect("failed to kill worker");
+    }
+}
+
 impl<P: ThreadPool> KvStore<P> {
-    pub fn open(path: impl Into<PathBuf>, tp: P) -> Result<Self> {
+    pub fn open(path: impl Into<PathBuf>, tp: P, opts: KvStoreOptions) -> Result<Self> {
         let dir_path = path.into();
         // load kvs file list
         let mut dir = read_dir(&dir_path)?
@@ -170,243 +553,600 @@ impl<P: ThreadPool> KvStore<P> {
             })
             .collect::<result::Result<Vec<_>, io::Error>>()?;
         dir.sort();
-        // load key-value entries
-        let table = DashMap::new();
-        let mut handles = BTreeMap::new();
-        let mut file_id: u32 = 0;
-        let mut uncompacted: u64 = 0;
+        let mut on_disk = Vec::new();
         for e in dir.iter() {
-            file_id = e
+            let id: u32 = e
                 .file_stem()
                 .expect("invalid file")
                 .to_str()
                 .unwrap()
                 .parse()?;
-            let mut file = File::open(e)?;
-            let load_entry = |offset, bytes: Vec<u8>| {
-                let cmd: Entry = serde_json::from_slice(&bytes).expect("Unmarshal failed");
-                if !cmd.is_del {
-                    let idx = Index::new(file_id, bytes.len() as u32, offset);
-                    if let Some(old) = table.insert(cmd.key, idx) {
-                        uncompacted += 4 + old.len as u64;
+            on_disk.push(id);
+        }
+        // segments written before manifest existed are replayed in id order
+        let mut manifest = match Manifest::open(&dir_path)? {
+            Some(m) => m,
+            None => {
+                let mut segments = on_disk.clone();
+                let next = on_disk.last().map_or(1, |id| id + 1);
+                if adopt_legacy_compacting(&dir_path, next)? {
+                    segments.insert(0, next);
+                    on_disk.push(next);
+                }
+                Manifest::create(&dir_path, segments)?
+            }
+        };
+        let segments = manifest.segments().to_vec();
+        remove_garbage(&dir_path, &segments)?;
+        // load key-value entries
+        let table = DashMap::new();
+        let stale = DashMap::new();
+        let now = now_millis();
+        let mut handles = BTreeMap::new();
+        for (i, &file_id) in segments.iter().enumerate() {
+            let path = kvs_path(&dir_path, file_id);
+            let file = File::open(&path)?;
+            // an expired record hides older ones like a tombstone
+            let load_entry = |key: Vec<u8>, idx: Index, is_del: bool| {
+                if !is_del && !idx.is_expired(now) {
+                    if let Some(old) = table.insert(key, idx) {
+                        add_stale(&stale, old.file, old.len);
                     }
                 } else {
-                    if let Some((_, old)) = table.remove(&cmd.key) {
-                        uncompacted += 4 + old.len as u64;
+                    if let Some((_, old)) = table.remove(&key) {
+                        add_stale(&stale, old.file, old.len);
                     }
-                    uncompacted += 4 + bytes.len() as u64;
+                    add_stale(&stale, idx.file, idx.len);
                 }
             };
-            iter_entries(&mut file, load_entry);
+            // prefer hint file, fall back to replay the whole segment
+            match read_hints(&dir_path, file_id) {
+                Ok(Some(hints)) => {
+                    debug!("loading segment {} from hint file", file_id);
+                    for h in hints {
+                        let idx = Index::new(file_id, h.len, h.offset, h.expires_at);
+                        load_entry(h.key, idx, h.is_del);
+                    }
+                    handles.insert(file_id, file);
+                    continue;
+                }
+                Ok(None) => {}
+                Err(err) => warn!("ignore invalid hint file of segment {}: {}", file_id, err),
+            }
+            let replay = |offset, bytes: Vec<u8>, ent: Entry| -> Result<()> {
+                load_entry(
+                    ent.key,
+                    Index::new(file_id, bytes.len() as u32, offset, ent.expires_at),
+                    ent.is_del,
+                );
+                Ok(())
+            };
+            if let Err(err) = iter_entries(file_id, &file, replay) {
+                match err.downcast_ref::<MyErr>() {
+                    // a torn tail left by crash is only possible in the newest segment
+                    Some(&MyErr::Corrupted(_, offset)) if i + 1 == segments.len() => {
+                        warn!("truncating torn tail of {:?} at offset {}", path, offset);
+                        File::options().write(true).open(&path)?.set_len(offset)?;
+                    }
+                    _ => return Err(err),
+                }
+            }
             handles.insert(file_id, file);
         }
-        // initialize data structure
-        file_id += 1;
+        // initialize data structure, never reuse an id found on disk
+        let file_id = on_disk
+            .iter()
+            .chain(segments.iter())
+            .max()
+            .map_or(1, |id| id + 1);
         let active = new_active_file(&dir_path, file_id)?;
+        manifest.add(file_id)?;
         handles.insert(file_id, active.try_clone()?);
         let handles = Arc::new(RwLock::new(handles));
+        let keys = Arc::new(RwLock::new(table.iter().map(|e| e.key().clone()).collect()));
         let indices = Arc::new(table);
-        let uncompacted = Arc::new(AtomicU64::new(uncompacted));
+        let stale = Arc::new(stale);
+        let next_id = Arc::new(AtomicU32::new(file_id + 1));
+        let manifest = Arc::new(Mutex::new(manifest));
         let mut store = KvStore {
             dir_path: dir_path.clone(),
             reader: Reader {
                 handles: handles.clone(),
                 indices: indices.clone(),
+                keys: keys.clone(),
             },
             writer: Arc::new(Mutex::new(Writer {
                 dir: dir_path.clone(),
                 file_id,
                 file: active,
                 indices: indices.clone(),
+                keys,
                 reader: handles.clone(),
-                uncompacted: uncompacted.clone(),
+                stale: stale.clone(),
+                next_id: next_id.clone(),
+                manifest: manifest.clone(),
+                segment_size: opts.segment_size,
+                sync: opts.sync,
+                version: 0,
             })),
+            pending: Arc::new(Mutex::new(Vec::new())),
             compactor: None,
+            syncer: None,
             tp: Arc::new(Mutex::new(tp)),
         };
+        if let SyncPolicy::Interval(interval) = opts.sync {
+            let syncer = Syncer {
+                writer: store.writer.clone(),
+                interval,
+            };
+            store.syncer = Some(syncer.run());
+        }
         // run comoactor in background
         let compactor = Compactor {
-            dir_path: dir_path,
+            dir_path,
+            writer: store.writer.clone(),
             reader: handles,
-            indices: indices,
-            uncompacted: uncompacted,
+            indices,
+            stale,
+            next_id,
+            manifest,
+            segment_size: opts.segment_size,
+            threshold: opts.compact_threshold,
+            interval: opts.compact_check,
         };
         store.compactor = Some(compactor.run());
         Ok(store)
     }
+    /// Queue a write for the writer, returns whether it is applied
+    async fn write(self, op: WriteOp) -> Result<bool> {
+        let (sdr, rcv) = oneshot::channel();
+        let w = self.writer.clone();
+        let pending = self.pending.clone();
+        self.tp.lock().unwrap().spawn(move || {
+            pending.lock().unwrap().push((op, sdr));
+            let mut w = w.lock().unwrap();
+            // take writes queued by others too, they will be committed together
+            let batch = mem::take(&mut *pending.lock().unwrap());
+            if !batch.is_empty() {
+                w.commit(batch);
+            }
+        });
+        rcv.await?
+    }
 }
 
-fn iter_entries<F: FnMut(u64, Vec<u8>)>(file: &mut File, mut f: F) {
-    let file_len = file.metadata().unwrap().len();
-    let mut offset: u64 = file.seek(SeekFrom::Start(0)).unwrap();
+struct Syncer {
+    writer: Arc<Mutex<Writer>>,
+    interval: Duration,
+}
+
+impl Syncer {
+    fn run(self) -> WorkerHandle {
+        let (sdr, rcv) = channel::bounded(0);
+        let h = thread::spawn(move || loop {
+            select! {
+                recv(rcv) -> _ => break,
+                default(self.interval) => {},
+            }
+            // sync without holding writer lock
+            let (id, file) = {
+                let w = self.writer.lock().unwrap();
+                (w.file_id, w.file.try_clone())
+            };
+            if let Err(err) = file.and_then(|f| f.sync_data()) {
+                error!("failed to sync segment {}: {}", id, err);
+            }
+        });
+        WorkerHandle {
+            handle: h,
+            sender: sdr,
+        }
+    }
+}
+
+/// Older releases compacted into a `compacting` file, which is renamed after
+/// its sources are removed. A crash in between leaves the only copy of some
+/// live records in it. Its records are copies of the oldest segments, so it
+/// is adopted as segment `id` in front of all others, which is harmless even
+/// if the sources survived. Returns whether it is adopted.
+fn adopt_legacy_compacting(dir: &Path, id: u32) -> Result<bool> {
+    let path = path_push(dir, COMPACTING);
+    if !path.exists() {
+        return Ok(false);
+    }
+    warn!(
+        "adopting interrupted compaction {:?} as segment {}",
+        path, id
+    );
+    let file = File::options().read(true).write(true).open(&path)?;
+    if let Err(err) = iter_entries(id, &file, |_, _, _| Ok(())) {
+        match err.downcast_ref::<MyErr>() {
+            // it was never synced, the tail may be torn
+            Some(&MyErr::Corrupted(_, offset)) => file.set_len(offset)?,
+            _ => return Err(err),
+        }
+    }
+    file.sync_all()?;
+    fs::rename(&path, kvs_path(dir, id))?;
+    Ok(true)
+}
+
+/// Remove files not referred by the manifest, which are left by interrupted
+/// compactions or segment cuts
+fn remove_garbage(dir: &Path, segments: &[u32]) -> Result<()> {
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let (Some(stem), Some(name)) = (path.file_stem(), path.file_name()) else {
+            continue;
+        };
+        let is_garbage = match path.extension().and_then(|ext| ext.to_str()) {
+            _ if name == MANIFEST_TMP || name == COMPACTING_HINT => true,
+            Some("kvs") | Some("hint") => stem
+                .to_str()
+                .and_then(|s| s.parse::<u32>().ok())
+                .is_some_and(|id| !segments.contains(&id)),
+            _ => false,
+        };
+        if is_garbage {
+            warn!("removing garbage file {:?}", path);
+            remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+fn read_hints(dir: &Path, id: u32) -> Result<Option<Vec<Hint>>> {
+    match fs::read(hint_path(dir, id)) {
+        Ok(buf) => Ok(Some(decode_hints(&buf)?)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Call `f` with the offset, raw bytes and decoded entry of every record in
+/// segment `id`. Records are read positionally, so it is safe against
+/// concurrent appends. A truncated or corrupt record stops the iteration with
+/// `MyErr::Corrupted`. Records of a group are only passed once the whole group
+/// is verified.
+fn iter_entries<F>(id: u32, file: &File, mut f: F) -> Result<()>
+where
+    F: FnMut(u64, Vec<u8>, Entry) -> Result<()>,
+{
+    let file_len = file.metadata()?.len();
+    let mut offset = 0;
     while offset < file_len {
-        let ent = read_entry(file).expect("failed to read entry");
-        f(offset + 4, ent);
-        offset = file.stream_position().unwrap();
+        let bytes = read_record(id, file, offset, file_len)?;
+        let len = bytes.len() as u64;
+        if record::is_group(&bytes) {
+            let corrupted = |_| MyErr::Corrupted(id, offset);
+            let members = record::split_group(&bytes)
+                .map_err(corrupted)?
+                .into_iter()
+                .map(|(pos, rec)| Ok((pos, rec.to_vec(), Entry::decode(rec)?)))
+                .collect::<Result<Vec<_>>>()
+                .map_err(corrupted)?;
+            for (pos, rec, ent) in members {
+                f(offset + pos as u64, rec, ent)?;
+            }
+        } else {
+            let ent = Entry::decode(&bytes).map_err(|_| MyErr::Corrupted(id, offset))?;
+            f(offset, bytes, ent)?;
+        }
+        offset += len;
     }
+    Ok(())
 }
 
-fn read_entry(file: &mut File) -> Result<Vec<u8>> {
-    let mut length = [0; 4];
-    file.read_exact(&mut length)?;
-    let length = u32::from_be_bytes(length);
-    let mut ent = vec![0; length as usize];
-    file.read_exact(&mut ent)?;
-    Ok(ent)
+fn read_record(id: u32, file: &File, offset: u64, file_len: u64) -> Result<Vec<u8>> {
+    let head_len = (file_len - offset).min(record::HEADER_LEN as u64);
+    let mut head = vec![0; head_len as usize];
+    pread_exact(file, &mut head, offset)?;
+    let len = match record::record_len(&head) {
+        Ok(len) if offset + len as u64 <= file_len => len,
+        _ => Err(MyErr::Corrupted(id, offset))?,
+    };
+    let mut bytes = vec![0; len];
+    pread_exact(file, &mut bytes, offset)?;
+    Ok(bytes)
 }
 
 struct Compactor {
     dir_path: PathBuf,
+    writer: Arc<Mutex<Writer>>,
     reader: Arc<RwLock<BTreeMap<u32, File>>>, // todo: lock-free
     // Lock order: reader -> indices (if both of them needed)
-    indices: Arc<DashMap<String, Index>>,
-    uncompacted: Arc<AtomicU64>,
+    indices: Arc<DashMap<Vec<u8>, Index>>,
+    stale: Arc<DashMap<u32, u64>>,
+    next_id: Arc<AtomicU32>,
+    manifest: Arc<Mutex<Manifest>>,
+    segment_size: u64,
+    threshold: u64,
+    interval: Duration,
+}
+
+/// A sealed segment chosen to be compacted
+struct Victim {
+    id: u32,
+    file: File,
+    // tombstones can be dropped if no older segment survives the compaction
+    keep_tombstones: bool,
+}
+
+/// A segment written by compaction
+struct Output {
+    id: u32,
+    file: File,
+    hints: Vec<Hint>,
+    stale: u64,
 }
 
 impl Compactor {
-    fn run(self) -> CompactorHandle {
+    fn run(self) -> WorkerHandle {
         let (sdr, rcv) = channel::bounded(0);
         // run compactor in backend
         let h = thread::spawn(move || loop {
             select! {
                 recv(rcv) -> _ => break,
-                default(Duration::from_secs(COMPACT_CHECK)) => {
+                default(self.interval) => {
                     debug!("checking compaction");
                 },
             }
-            if self.uncompacted.load(Ordering::Acquire) < COMPACT_THRESHOLD {
+            self.expire(now_millis());
+            let stale: u64 = self.stale.iter().map(|s| *s.value()).sum();
+            if stale < self.threshold {
                 continue;
             }
-            self.compact()
+            if let Err(err) = self.compact() {
+                error!("compaction failed: {}", err);
+            }
         });
-        CompactorHandle {
+        WorkerHandle {
             handle: h,
             sender: sdr,
         }
     }
-    fn compact(&self) {
-        let path = path_push(&self.dir_path, "compacting");
-        let mut compact_dst = File::options()
-            .append(true)
-            .create_new(true)
-            .open(&path)
-            .expect(format!("failed to create compacting file: {:?}", path).as_str());
-        let compact_src = {
-            // todo: optimize strategy to choose compacting files
-            let hanldes = self.reader.read().unwrap();
-            let mut tk = hanldes.iter().take(2);
-            let mut c: Vec<(u32, File)> = Vec::new();
-            while let Some((&id, file)) = tk.next() {
-                c.push((id, file.try_clone().unwrap()));
-            }
-            c
-        };
-        // write to compacting destination
-        let mut moved: Vec<(String, u32, u64, u64)> = Vec::new();
-        for (id, file) in &compact_src {
-            let compact_log = |offset, bytes: Vec<u8>| {
-                let ent: Entry = serde_json::from_slice(&bytes).expect("Unmarshal failed");
-                if ent.is_del {
-                    if !self.indices.contains_key(&ent.key) {
-                        // maybe a put Entry exists in previous log
-                        append_entry_bytes(&mut compact_dst, bytes).expect("failed to write");
-                    }
+    fn compact(&self) -> Result<()> {
+        let victims = self.pick_victims()?;
+        if victims.is_empty() {
+            debug!("no segment is worth compacting");
+            return Ok(());
+        }
+        // no key expired at `now` is left in the index
+        let now = now_millis();
+        self.expire(now);
+        // copy live records into new segments
+        let mut outputs: Vec<Output> = Vec::new();
+        let mut moved: Vec<(Vec<u8>, Index, u32, u64)> = Vec::new();
+        for v in &victims {
+            let compact_log = |offset, mut bytes: Vec<u8>, ent: Entry| -> Result<()> {
+                let expired = !ent.is_del && ent.expires_at.is_some_and(|t| t <= now);
+                let live = if ent.is_del || expired {
+                    // maybe a put Entry exists in older segment
+                    v.keep_tombstones && !self.indices.contains_key(&ent.key)
                 } else {
-                    if let Some(idx) = self.load_index(&ent.key) {
-                        if idx.file == *id && idx.offset == offset {
-                            let pos = append_entry_bytes(&mut compact_dst, bytes)
-                                .expect("failed to write");
-                            moved.push((ent.key, *id, offset, pos));
-                        }
-                    }
+                    self.load_index(&ent.key)
+                        .is_some_and(|idx| idx.file == v.id && idx.offset == offset)
+                };
+                if !live {
+                    return Ok(());
+                }
+                let is_del = ent.is_del || expired;
+                if expired {
+                    // keep it as a tombstone without its value
+                    bytes = Entry::del(ent.key.clone()).encode();
                 }
+                let out = self.output(&mut outputs)?;
+                let len = bytes.len() as u32;
+                let pos = append_entry_bytes(&mut out.file, &bytes)?;
+                if is_del {
+                    out.stale += len as u64;
+                } else {
+                    let old = Index::new(v.id, len, offset, ent.expires_at);
+                    moved.push((ent.key.clone(), old, out.id, pos));
+                }
+                out.hints.push(Hint {
+                    key: ent.key,
+                    offset: pos,
+                    len,
+                    is_del,
+                    expires_at: ent.expires_at.filter(|_| !is_del),
+                });
+                Ok(())
             };
-            debug!("start to compact file {}.kvs", id);
-            concurrent_iter_entries(file, compact_log);
-        }
-        // clean source files
-        let mut off: u64 = 0;
-        let mut handles = self.reader.write().unwrap();
-        for (id, src) in compact_src {
-            off += src.metadata().unwrap().len();
-            handles.remove(&id).expect("file handle not exist");
-            remove_file(kvs_path(&self.dir_path, id)).unwrap();
-            debug!("file {}.kvs removed", id);
-        }
-        // adopt compacted file
-        drop(compact_dst); // reopen in Read-Only mode
-        let path_dst = kvs_path(&self.dir_path, 1);
-        rename(path, &path_dst).expect("compaction failed");
-        let compacted = File::open(path_dst).unwrap();
-        off -= compacted.metadata().unwrap().len();
-        handles.insert(1, compacted);
-        for (key, old_f, old_pos, pos) in moved {
-            if let Some(mut idx) = self.indices.get_mut(&key) {
-                // make sure index is unmodified
-                if idx.file == old_f && idx.offset == old_pos {
-                    idx.file = 1;
-                    idx.offset = pos;
+            debug!("start to compact file {}.kvs", v.id);
+            if let Err(err) = iter_entries(v.id, &v.file, compact_log) {
+                self.discard(&outputs);
+                return Err(err);
+            }
+        }
+        let victim_ids: Vec<u32> = victims.iter().map(|v| v.id).collect();
+        let output_ids: Vec<u32> = outputs.iter().map(|o| o.id).collect();
+        if let Err(err) = self.seal(&outputs) {
+            self.discard(&outputs);
+            return Err(err);
+        }
+        // switch live segments atomically
+        if let Err(err) = self
+            .manifest
+            .lock()
+            .unwrap()
+            .compact(&victim_ids, &output_ids)
+        {
+            self.discard(&outputs);
+            return Err(err);
+        }
+        let mut freed: u64 = 0;
+        {
+            let mut handles = self.reader.write().unwrap();
+            for v in &victims {
+                freed += v.file.metadata()?.len();
+                handles.remove(&v.id).expect("file handle not exist");
+                self.stale.remove(&v.id);
+            }
+            for out in outputs {
+                freed = freed.saturating_sub(out.file.metadata()?.len());
+                self.stale.insert(out.id, out.stale);
+                handles.insert(out.id, out.file);
+            }
+            for (key, old, id, pos) in moved {
+                if let Some(mut idx) = self.indices.get_mut(&key) {
+                    // make sure index is unmodified
+                    if idx.file == old.file && idx.offset == old.offset {
+                        idx.file = id;
+                        idx.offset = pos;
+                        continue;
+                    }
                 }
+                add_stale(&self.stale, id, old.len);
             }
+            self.stale.retain(|id, _| handles.contains_key(id));
         }
-        self.uncompacted.fetch_sub(off, Ordering::Relaxed);
-        info!("compaction finished, {} bytes disk freed", off);
+        // clean victims
+        for id in victim_ids {
+            remove_segment(&self.dir_path, id);
+        }
+        info!(
+            "compaction finished, segments {:?} -> {:?}, {} bytes disk freed",
+            victims.iter().map(|v| v.id).collect::<Vec<_>>(),
+            output_ids,
+            freed
+        );
+        Ok(())
+    }
+    /// Choose sealed segments with enough stale bytes
+    fn pick_victims(&self) -> Result<Vec<Victim>> {
+        let segments = self.manifest.lock().unwrap().segments().to_vec();
+        // the last segment is the active one
+        let sealed = &segments[..segments.len().saturating_sub(1)];
+        let handles = self.reader.read().unwrap();
+        let mut victims = Vec::new();
+        let mut all_older_compacted = true;
+        for id in sealed {
+            let file = match handles.get(id) {
+                Some(file) => file.try_clone()?,
+                None => continue,
+            };
+            let size = file.metadata()?.len();
+            let stale = self.stale.get(id).map_or(0, |s| *s);
+            if size == 0 || stale as f64 >= size as f64 * COMPACT_RATIO {
+                victims.push(Victim {
+                    id: *id,
+                    file,
+                    keep_tombstones: !all_older_compacted,
+                });
+            } else {
+                all_older_compacted = false;
+            }
+        }
+        Ok(victims)
+    }
+    /// Current output segment, a new one is created if it is full
+    fn output<'a>(&self, outputs: &'a mut Vec<Output>) -> Result<&'a mut Output> {
+        let full = match outputs.last() {
+            Some(out) => out.file.metadata()?.len() >= self.segment_size,
+            None => true,
+        };
+        if full {
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let file = new_active_file(&self.dir_path, id)?;
+            debug!("compacting into segment {}", id);
+            outputs.push(Output {
+                id,
+                file,
+                hints: Vec::new(),
+                stale: 0,
+            });
+        }
+        Ok(outputs.last_mut().unwrap())
+    }
+    /// Write hint files and make outputs durable
+    fn seal(&self, outputs: &[Output]) -> Result<()> {
+        for out in outputs {
+            let mut hint = File::create(hint_path(&self.dir_path, out.id))?;
+            hint.write_all(&encode_hints(&out.hints))?;
+            hint.sync_all()?;
+            out.file.sync_all()?;
+        }
+        Ok(())
     }
-    fn load_index(&self, key: &String) -> Option<Index> {
+    fn discard(&self, outputs: &[Output]) {
+        for out in outputs {
+            remove_segment(&self.dir_path, out.id);
+        }
+    }
+    fn load_index(&self, key: &[u8]) -> Option<Index> {
         self.indices.get(key).map(|idx| idx.clone())
     }
+    /// Drop keys expired at `now` from the index
+    fn expire(&self, now: u64) {
+        let expired: Vec<Vec<u8>> = self
+            .indices
+            .iter()
+            .filter(|e| e.value().is_expired(now))
+            .map(|e| e.key().clone())
+            .collect();
+        if !expired.is_empty() {
+            self.writer.lock().unwrap().expire(expired, now);
+        }
+    }
 }
 
-fn concurrent_iter_entries<F: FnMut(u64, Vec<u8>)>(file: &File, mut f: F) {
-    let file_len = file.metadata().unwrap().len();
-    let mut offset = 0;
-    while offset < file_len {
-        let mut length = [0; 4];
-        pread_exact(file, &mut length, offset).unwrap();
-        offset += 4;
-        let length = u32::from_be_bytes(length);
-        let mut ent = vec![0; length as usize];
-        pread_exact(file, &mut ent, offset).unwrap();
-        f(offset, ent);
-        offset += length as u64;
-    }
+/// Random delay between half and one and a half of `delay`, so that
+/// transactions conflicting with each other do not retry in lockstep
+fn jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay / 2 + delay.mul_f64((random % 1024) as f64 / 1024.0)
 }
 
-fn pread_exact(file: &File, mut buf: &mut [u8], mut offset: u64) -> Result<()> {
-    while buf.len() > 0 {
-        let r = file.seek_read(buf, offset)?;
-        buf = &mut buf[r..];
-        offset += r as u64;
+fn add_stale(stale: &DashMap<u32, u64>, id: u32, len: u32) {
+    *stale.entry(id).or_insert(0) += len as u64;
+}
+
+/// Remove segment file and its hint file
+fn remove_segment(dir: &Path, id: u32) {
+    for path in [kvs_path(dir, id), hint_path(dir, id)] {
+        if let Err(err) = remove_file(&path) {
+            if err.kind() != io::ErrorKind::NotFound {
+                error!("failed to remove {:?}: {}", path, err);
+            }
+        }
     }
-    Ok(())
+    debug!("file {}.kvs removed", id);
 }
 
-fn append_entry_bytes(file: &mut File, ent: Vec<u8>) -> Result<u64> {
-    let len = ent.len() as u32;
-    file.write_all(&len.to_be_bytes())?;
-    let offset = file.metadata().unwrap().len();
-    file.write_all(&ent)?;
+fn append_entry_bytes(file: &mut File, ent: &[u8]) -> Result<u64> {
+    let offset = file.metadata()?.len();
+    file.write_all(ent)?;
     Ok(offset)
 }
 
 impl<ThreadPool> Drop for KvStore<ThreadPool> {
     fn drop(&mut self) {
-        // kill compactor
+        // kill background workers
         if let Some(c) = self.compactor.take() {
-            c.sender
-                .send(())
-                .expect("failed to notify compactor to exit");
-            c.handle.join().expect("failed to kill compactor");
+            c.stop();
+            if let Some(s) = self.syncer.take() {
+                s.stop();
+            }
+            // writes are durable after a graceful close whatever the policy
+            let w = self.writer.lock().unwrap();
+            if let Err(err) = w.file.sync_data() {
+                error!("failed to sync segment {}: {}", w.file_id, err);
+            }
             info!("KvStore closed gracefully!");
         }
     }
 }
 
-fn kvs_path(dir: &PathBuf, id: u32) -> PathBuf {
+fn kvs_path(dir: &Path, id: u32) -> PathBuf {
     path_push(dir, format!("{:09}.kvs", id).as_str())
 }
 
-fn new_active_file(dir: &PathBuf, id: u32) -> Result<File> {
+fn hint_path(dir: &Path, id: u32) -> PathBuf {
+    path_push(dir, format!("{:09}.hint", id).as_str())
+}
+
+fn new_active_file(dir: &Path, id: u32) -> Result<File> {
     let path = kvs_path(dir, id);
     trace!("creating active file {:?}", path);
     let file = File::options()
@@ -417,70 +1157,122 @@ fn new_active_file(dir: &PathBuf, id: u32) -> Result<File> {
     Ok(file)
 }
 
-fn path_push(dir: &PathBuf, f: &str) -> PathBuf {
-    let mut dir = dir.clone();
-    dir.push(f);
-    dir
+fn path_push(dir: &Path, f: &str) -> PathBuf {
+    dir.join(f)
 }
 
 #[async_trait]
 impl<P: ThreadPool> KvsEngine for KvStore<P> {
     /// Insert/Update key-value
-    async fn set(self, key: String, val: String) -> Result<()> {
+    async fn set(self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        self.write(WriteOp::Set(key, val, None)).await.map(|_| ())
+    }
+    /// Insert/Update key-value, which expires after `ttl`
+    async fn set_with_ttl(self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expiry = expires_at(ttl);
+        self.write(WriteOp::Set(key, val, Some(expiry)))
+            .await
+            .map(|_| ())
+    }
+    /// Remove value by key
+    async fn remove(self, key: Vec<u8>) -> Result<()> {
+        self.write(WriteOp::Remove(key)).await.map(|_| ())
+    }
+    /// Compare and swap atomically against other writes
+    async fn compare_and_swap(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.write(WriteOp::CompareAndSwap(key, expected, new))
+            .await
+    }
+    async fn apply_batch(self, batch: WriteBatch) -> Result<()> {
+        self.write(WriteOp::Batch(batch)).await.map(|_| ())
+    }
+    /// Optimistic, `f` runs without lock and its reads are validated against
+    /// versions of the keys by the writer, which applies its writes. Reads of
+    /// `f` failing are validated too, since they may be of different commits
+    async fn transaction<F, T>(self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let f = Arc::new(f);
+        let mut backoff = TX_BACKOFF;
+        for attempt in 1..=TX_ATTEMPTS {
+            let (sdr, rcv) = oneshot::channel();
+            let (r, f) = (self.reader.clone(), f.clone());
+            self.tp.lock().unwrap().spawn(move || {
+                let mut tx = KvsTransaction::new(&r);
+                let rlt = f(&mut tx);
+                let op = match rlt {
+                    Ok(_) => tx.into_write(),
+                    Err(_) => tx.into_check(),
+                };
+                if sdr.send((rlt, op)).is_err() {
+                    debug!("transaction dropped by receiver");
+                }
+            });
+            let (rlt, op) = rcv.await?;
+            if self.clone().write(op).await? {
+                return rlt;
+            }
+            if attempt < TX_ATTEMPTS {
+                let delay = jitter(backoff);
+                debug!("transaction conflicted, retrying in {:?}", delay);
+                tokio::time::sleep(delay).await;
+                backoff *= 2;
+            }
+        }
+        Err(MyErr::Conflict(TX_ATTEMPTS))?
+    }
+    /// Get value by key, sequential consistency is guaranteed
+    async fn get(self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let (sdr, rcv) = oneshot::channel();
-        let w = self.writer.clone();
+        let r = self.reader.clone();
         self.tp.lock().unwrap().spawn(move || {
-            let rlt = w.lock().unwrap().set(key, val);
+            let rlt = r.get(key);
             sdr.send(rlt).expect("oneshot send failed");
         });
         rcv.await?
     }
-    /// Remove value by key
-    async fn remove(self, key: String) -> Result<()> {
+    /// Scan key range, pairs written during the scan may or may not be seen
+    async fn scan(
+        self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let upper = match end {
+            Some(end) if start >= end => return Ok(Vec::new()),
+            Some(end) => Bound::Excluded(end),
+            None => Bound::Unbounded,
+        };
         let (sdr, rcv) = oneshot::channel();
-        let w = self.writer.clone();
+        let r = self.reader.clone();
         self.tp.lock().unwrap().spawn(move || {
-            let rlt = w.lock().unwrap().remove(key);
+            let rlt = r.scan(Bound::Included(start), upper, |_| true, limit);
             sdr.send(rlt).expect("oneshot send failed");
         });
         rcv.await?
     }
-    /// Get value by key, sequential consistency is guaranteed
-    async fn get(self, key: String) -> Result<Option<String>> {
+    /// Scan keys with prefix, pairs written during the scan may or may not be seen
+    async fn scan_prefix(self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
         let (sdr, rcv) = oneshot::channel();
         let r = self.reader.clone();
         self.tp.lock().unwrap().spawn(move || {
-            let rlt = r.get(key);
+            let lower = Bound::Included(prefix.clone());
+            let rlt = r.scan(lower, Bound::Unbounded, |k| k.starts_with(&prefix), limit);
             sdr.send(rlt).expect("oneshot send failed");
         });
         rcv.await?
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Entry {
-    key: String,
-    val: String,
-    is_del: bool,
-}
-
-impl Entry {
-    fn put(key: String, val: String) -> Self {
-        let is_del = false;
-        Entry { key, val, is_del }
-    }
-    fn del(key: String) -> Self {
-        let val = String::new();
-        let is_del = true;
-        Entry { key, val, is_del }
-    }
-}
-
 fn append_entry(file: &mut File, ent: Entry) -> Result<(u32, u64)> {
-    let ent = serde_json::to_vec(&ent)?;
-    let len = ent.len() as u32;
-    file.write_all(&len.to_be_bytes())?;
-    let offset = file.metadata().unwrap().len();
-    file.write_all(&ent)?;
-    Ok((len, offset))
+    let ent = ent.encode();
+    let offset = append_entry_bytes(file, &ent)?;
+    Ok((ent.len() as u32, offset))
 }
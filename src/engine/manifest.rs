@@ -0,0 +1,246 @@
+//! Manifest is an append-only log of edits to the list of live segments.
+//! Segments are replayed in the order of this list when the store is opened,
+//! which is not necessarily the order of their ids.
+//!
+//! Every edit is synced before it takes effect, an edit torn by crash is
+//! truncated away on open. The log is rewritten as a single snapshot once it
+//! holds too many edits.
+//!
+//! Edit:
+//! | len: u32 | crc: u32 | op: u8 | payload |
+//! Integers are big-endian, `len` and `crc` cover `op` and `payload`.
+use crate::{MyErr, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+pub const MANIFEST: &str = "MANIFEST";
+pub const MANIFEST_TMP: &str = "MANIFEST.tmp";
+const MAX_EDITS: usize = 1024;
+
+const OP_SNAPSHOT: u8 = 1;
+const OP_ADD: u8 = 2;
+const OP_COMPACT: u8 = 3;
+
+enum Edit<'a> {
+    /// Replace the whole list
+    Snapshot(&'a [u32]),
+    /// Append a new active segment
+    Add(u32),
+    /// Replace `victims` with `outputs`, at the position of the last victim
+    Compact {
+        victims: &'a [u32],
+        outputs: &'a [u32],
+    },
+}
+
+pub struct Manifest {
+    dir: PathBuf,
+    file: File,
+    segments: Vec<u32>,
+    edits: usize,
+}
+
+impl Manifest {
+    /// Load manifest from `dir`, returns `None` if there is none
+    pub fn open(dir: &Path) -> Result<Option<Manifest>> {
+        let path = manifest_path(dir);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let buf = fs::read(&path)?;
+        let mut segments = Vec::new();
+        let mut edits = 0;
+        let mut rest = buf.as_slice();
+        while !rest.is_empty() {
+            let (op, payload, tail) = match decode_edit(rest) {
+                Ok(edit) => edit,
+                Err(_) if is_tail(rest) => {
+                    let offset = (buf.len() - rest.len()) as u64;
+                    warn!("truncating torn tail of manifest at offset {}", offset);
+                    File::options().write(true).open(&path)?.set_len(offset)?;
+                    break;
+                }
+                Err(_) => Err(MyErr::CorruptedManifest)?,
+            };
+            match op {
+                OP_SNAPSHOT => segments = read_ids(payload)?.0,
+                OP_ADD => {
+                    let id = read_ids(payload)?.0.first().copied();
+                    segments.push(id.ok_or(MyErr::InvalidRecord)?);
+                }
+                OP_COMPACT => {
+                    let (victims, outputs) = read_ids(payload)?;
+                    apply_compact(&mut segments, &victims, &outputs);
+                }
+                _ => Err(MyErr::CorruptedManifest)?,
+            }
+            edits += 1;
+            rest = tail;
+        }
+        debug!("manifest loaded, segments={:?}", segments);
+        let file = File::options().append(true).open(path)?;
+        let mut manifest = Manifest {
+            dir: dir.to_owned(),
+            file,
+            segments,
+            edits,
+        };
+        manifest.maybe_rewrite()?;
+        Ok(Some(manifest))
+    }
+    /// Create a manifest holding `segments`, replacing any existing one
+    pub fn create(dir: &Path, segments: Vec<u32>) -> Result<Manifest> {
+        let tmp = dir.join(MANIFEST_TMP);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&encode_edit(&Edit::Snapshot(&segments)))?;
+        file.sync_all()?;
+        fs::rename(&tmp, manifest_path(dir))?;
+        sync_dir(dir)?;
+        let file = File::options().append(true).open(manifest_path(dir))?;
+        Ok(Manifest {
+            dir: dir.to_owned(),
+            file,
+            segments,
+            edits: 1,
+        })
+    }
+    /// Live segments in replay order, the last one is the active segment
+    pub fn segments(&self) -> &[u32] {
+        &self.segments
+    }
+    pub fn add(&mut self, id: u32) -> Result<()> {
+        self.append(&Edit::Add(id))?;
+        self.segments.push(id);
+        self.maybe_rewrite()
+    }
+    pub fn compact(&mut self, victims: &[u32], outputs: &[u32]) -> Result<()> {
+        self.append(&Edit::Compact { victims, outputs })?;
+        apply_compact(&mut self.segments, victims, outputs);
+        self.maybe_rewrite()
+    }
+    fn append(&mut self, edit: &Edit) -> Result<()> {
+        // segment files referred by the edit must survive a crash
+        sync_dir(&self.dir)?;
+        self.file.write_all(&encode_edit(edit))?;
+        self.file.sync_data()?;
+        self.edits += 1;
+        Ok(())
+    }
+    fn maybe_rewrite(&mut self) -> Result<()> {
+        if self.edits > MAX_EDITS {
+            debug!("rewriting manifest with {} edits", self.edits);
+            *self = Manifest::create(&self.dir, self.segments.clone())?;
+        }
+        Ok(())
+    }
+}
+
+fn apply_compact(segments: &mut Vec<u32>, victims: &[u32], outputs: &[u32]) {
+    let last = segments
+        .iter()
+        .rposition(|id| victims.contains(id))
+        .unwrap_or(segments.len());
+    let pos = segments[..last]
+        .iter()
+        .filter(|id| !victims.contains(id))
+        .count();
+    segments.retain(|id| !victims.contains(id));
+    segments.splice(pos..pos, outputs.iter().copied());
+}
+
+fn encode_edit(edit: &Edit) -> Vec<u8> {
+    let mut body = Vec::new();
+    match edit {
+        Edit::Snapshot(ids) => {
+            body.push(OP_SNAPSHOT);
+            write_ids(&mut body, ids);
+        }
+        Edit::Add(id) => {
+            body.push(OP_ADD);
+            write_ids(&mut body, &[*id]);
+        }
+        Edit::Compact { victims, outputs } => {
+            body.push(OP_COMPACT);
+            write_ids(&mut body, victims);
+            write_ids(&mut body, outputs);
+        }
+    }
+    let mut buf = Vec::with_capacity(8 + body.len());
+    buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+    buf.extend_from_slice(&body);
+    buf
+}
+
+/// Whether the first edit of `buf` reaches the end of file, so that it may be
+/// torn by a crash while appending
+fn is_tail(buf: &[u8]) -> bool {
+    if buf.len() < 8 {
+        return true;
+    }
+    let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    8 + len >= buf.len()
+}
+
+/// Split the first edit of `buf` into op, payload and the remaining bytes
+fn decode_edit(buf: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+    if buf.len() < 9 {
+        Err(MyErr::InvalidRecord)?
+    }
+    let len = u32::from_be_bytes(buf[0..4].try_into()?) as usize;
+    let crc = u32::from_be_bytes(buf[4..8].try_into()?);
+    if len == 0 || buf.len() < 8 + len {
+        Err(MyErr::InvalidRecord)?
+    }
+    let (body, rest) = buf[8..].split_at(len);
+    if crc32fast::hash(body) != crc {
+        Err(MyErr::InvalidRecord)?
+    }
+    Ok((body[0], &body[1..], rest))
+}
+
+fn write_ids(buf: &mut Vec<u8>, ids: &[u32]) {
+    buf.extend_from_slice(&(ids.len() as u32).to_be_bytes());
+    for id in ids {
+        buf.extend_from_slice(&id.to_be_bytes());
+    }
+}
+
+/// Read up to two id lists from payload
+fn read_ids(mut payload: &[u8]) -> Result<(Vec<u32>, Vec<u32>)> {
+    let mut lists = Vec::new();
+    while !payload.is_empty() {
+        if payload.len() < 4 {
+            Err(MyErr::InvalidRecord)?
+        }
+        let n = u32::from_be_bytes(payload[0..4].try_into()?) as usize;
+        let ids = payload[4..]
+            .chunks_exact(4)
+            .take(n)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>();
+        if ids.len() != n {
+            Err(MyErr::InvalidRecord)?
+        }
+        payload = &payload[4 + 4 * n..];
+        lists.push(ids);
+    }
+    let mut lists = lists.into_iter();
+    let first = lists.next().ok_or(MyErr::InvalidRecord)?;
+    Ok((first, lists.next().unwrap_or_default()))
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST)
+}
+
+/// Make file creations and renames in `dir` durable
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
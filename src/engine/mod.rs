@@ -1,16 +1,92 @@
+mod batch;
+mod hint;
 pub mod kvs_eng;
+mod manifest;
+mod pread;
+mod record;
 pub mod sled_eng;
-pub use kvs_eng::KvStore;
+pub use batch::{BatchOp, WriteBatch};
+pub use kvs_eng::{KvStore, KvStoreOptions, SyncPolicy};
 pub use sled_eng::SledKvsEngine;
 
 use crate::Result;
 use async_trait::async_trait;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[async_trait]
 pub trait KvsEngine: Clone + Send + 'static {
-    async fn set(self, key: String, value: String) -> Result<()>;
+    async fn set(self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
 
-    async fn get(self, key: String) -> Result<Option<String>>;
+    /// Set a value which `get` and scans no longer see after `ttl`. A later
+    /// `set` of the key clears its expiry.
+    async fn set_with_ttl(self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
 
-    async fn remove(self, key: String) -> Result<()>;
+    async fn get(self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    async fn remove(self, key: Vec<u8>) -> Result<()>;
+
+    /// Apply writes of `batch` in order, atomically against a crash
+    async fn apply_batch(self, batch: WriteBatch) -> Result<()>;
+
+    /// Replace the value of `key` with `new` if it is `expected`, `None`
+    /// meaning absent on either side. Returns whether it is swapped. A swapped
+    /// value has no expiry.
+    async fn compare_and_swap(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Set a value only if `key` is absent, returns whether it is set
+    async fn set_if_absent(self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value)).await
+    }
+
+    /// Run `f` in a transaction. Its writes are committed atomically, only if
+    /// no key it read was written by others meanwhile, otherwise `f` is run
+    /// again. An engine may give up with `MyErr::Conflict` after some attempts.
+    /// An error returned by `f` aborts the transaction, unless a key it read
+    /// was written meanwhile, then `f` is run again too.
+    async fn transaction<F, T>(self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T> + Send + Sync + 'static,
+        T: Send + 'static;
+
+    /// Up to `limit` pairs with keys in `[start, end)` in key order, `end` is
+    /// unbounded if `None`
+    async fn scan(
+        self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// Up to `limit` pairs with keys starting with `prefix` in key order
+    async fn scan_prefix(self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// Reads and writes of a transaction, see `KvsEngine::transaction`. Writes
+/// are seen by later reads of the same transaction, and by others only once
+/// it is committed.
+pub trait Transaction {
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Set a value without expiry
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Removing an absent key is not an error
+    fn remove(&mut self, key: Vec<u8>) -> Result<()>;
+}
+
+/// Milliseconds since the Unix epoch
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Expiry of a value set now to live for `ttl`
+fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
 }
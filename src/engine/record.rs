@@ -0,0 +1,208 @@
+//! On-disk record format of kvs segment files.
+//!
+//! Binary record (current):
+//! | version: u8 | flags: u8 | key_len: u32 | val_len: u32 | crc: u32 | expiry | key | val |
+//! The highest bit of `version` is always set, integers are big-endian.
+//! `crc` is the CRC32 of all other bytes of the record. `expiry` is a u64 of
+//! milliseconds since the Unix epoch, present only if `FLAG_EXPIRES` is set.
+//!
+//! Group record, written by a batch:
+//! | version: u8 | flags: u8 | 0: u32 | body_len: u32 | crc: u32 | record | record | ... |
+//! `FLAG_GROUP` is set, the body is made of binary records. The group is
+//! checksummed as a whole, so that a torn group is dropped entirely on replay.
+//!
+//! Legacy record (written by older releases, read only):
+//! | len: u32 | json encoded entry |
+//! `len` is far below 2GiB, so its first byte never has the highest bit set.
+use crate::{MyErr, Result};
+use serde::Deserialize;
+
+/// Length of the binary record header
+pub const HEADER_LEN: usize = 14;
+const CRC_OFFSET: usize = 10;
+/// Length of the legacy record prefix
+const LEGACY_HEADER_LEN: usize = 4;
+
+const BINARY_MARK: u8 = 0x80;
+const VERSION: u8 = BINARY_MARK | 1;
+const FLAG_TOMBSTONE: u8 = 1;
+const FLAG_EXPIRES: u8 = 2;
+const FLAG_GROUP: u8 = 4;
+const EXPIRY_LEN: usize = 8;
+
+#[derive(Debug)]
+pub struct Entry {
+    pub key: Vec<u8>,
+    pub val: Vec<u8>,
+    pub is_del: bool,
+    /// Milliseconds since the Unix epoch after which the value is gone
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct JsonEntry {
+    key: String,
+    val: String,
+    is_del: bool,
+}
+
+impl Entry {
+    pub fn put(key: Vec<u8>, val: Vec<u8>, expires_at: Option<u64>) -> Self {
+        let is_del = false;
+        Entry {
+            key,
+            val,
+            is_del,
+            expires_at,
+        }
+    }
+    pub fn del(key: Vec<u8>) -> Self {
+        let val = Vec::new();
+        let is_del = true;
+        let expires_at = None;
+        Entry {
+            key,
+            val,
+            is_del,
+            expires_at,
+        }
+    }
+    /// Encode entry into a binary record
+    pub fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.is_del {
+            flags |= FLAG_TOMBSTONE;
+        }
+        if self.expires_at.is_some() {
+            flags |= FLAG_EXPIRES;
+        }
+        let len = HEADER_LEN + EXPIRY_LEN + self.key.len() + self.val.len();
+        let mut buf = Vec::with_capacity(len);
+        buf.push(VERSION);
+        buf.push(flags);
+        buf.extend_from_slice(&(self.key.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&(self.val.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&[0; 4]);
+        if let Some(expires_at) = self.expires_at {
+            buf.extend_from_slice(&expires_at.to_be_bytes());
+        }
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.val);
+        let crc = checksum(&buf);
+        buf[CRC_OFFSET..HEADER_LEN].copy_from_slice(&crc.to_be_bytes());
+        buf
+    }
+    /// Decode a whole record, either binary or legacy
+    pub fn decode(rec: &[u8]) -> Result<Self> {
+        if record_len(rec)? != rec.len() || is_group(rec) {
+            Err(MyErr::InvalidRecord)?
+        }
+        if is_legacy(rec) {
+            let ent: JsonEntry = serde_json::from_slice(&rec[LEGACY_HEADER_LEN..])?;
+            return Ok(Entry {
+                key: ent.key.into_bytes(),
+                val: ent.val.into_bytes(),
+                is_del: ent.is_del,
+                expires_at: None,
+            });
+        }
+        if read_u32(&rec[CRC_OFFSET..HEADER_LEN]) != checksum(rec) {
+            Err(MyErr::InvalidRecord)?
+        }
+        let klen = read_u32(&rec[2..6]) as usize;
+        let (expires_at, body) = if rec[1] & FLAG_EXPIRES != 0 {
+            let (expiry, body) = rec[HEADER_LEN..].split_at(EXPIRY_LEN);
+            (Some(u64::from_be_bytes(expiry.try_into()?)), body)
+        } else {
+            (None, &rec[HEADER_LEN..])
+        };
+        Ok(Entry {
+            key: body[..klen].to_vec(),
+            val: body[klen..].to_vec(),
+            is_del: rec[1] & FLAG_TOMBSTONE != 0,
+            expires_at,
+        })
+    }
+}
+
+/// Encode entries into a group record, returns it and the offset and length
+/// of every entry in it
+pub fn encode_group(ents: &[Entry]) -> (Vec<u8>, Vec<(usize, usize)>) {
+    let mut buf = Vec::new();
+    buf.push(VERSION);
+    buf.push(FLAG_GROUP);
+    buf.extend_from_slice(&[0; 12]);
+    let mut members = Vec::with_capacity(ents.len());
+    for ent in ents {
+        let rec = ent.encode();
+        members.push((buf.len(), rec.len()));
+        buf.extend_from_slice(&rec);
+    }
+    let body_len = (buf.len() - HEADER_LEN) as u32;
+    buf[6..10].copy_from_slice(&body_len.to_be_bytes());
+    let crc = checksum(&buf);
+    buf[CRC_OFFSET..HEADER_LEN].copy_from_slice(&crc.to_be_bytes());
+    (buf, members)
+}
+
+pub fn is_group(rec: &[u8]) -> bool {
+    !is_legacy(rec) && rec.get(1).is_some_and(|flags| flags & FLAG_GROUP != 0)
+}
+
+/// Split a whole group record into its records and their offsets in it
+pub fn split_group(rec: &[u8]) -> Result<Vec<(usize, &[u8])>> {
+    if record_len(rec)? != rec.len() || read_u32(&rec[CRC_OFFSET..HEADER_LEN]) != checksum(rec) {
+        Err(MyErr::InvalidRecord)?
+    }
+    let mut members = Vec::new();
+    let mut pos = HEADER_LEN;
+    while pos < rec.len() {
+        let rest = &rec[pos..];
+        let len = record_len(rest)?;
+        if len > rest.len() || is_legacy(rest) || is_group(rest) {
+            Err(MyErr::InvalidRecord)?
+        }
+        members.push((pos, &rest[..len]));
+        pos += len;
+    }
+    Ok(members)
+}
+
+/// Total length of the record starting with `head`, which must hold at least
+/// `HEADER_LEN` bytes or the whole record.
+pub fn record_len(head: &[u8]) -> Result<usize> {
+    if is_legacy(head) {
+        if head.len() < LEGACY_HEADER_LEN {
+            Err(MyErr::InvalidRecord)?
+        }
+        return Ok(LEGACY_HEADER_LEN + read_u32(&head[..LEGACY_HEADER_LEN]) as usize);
+    }
+    if head.len() < HEADER_LEN || head[0] != VERSION {
+        Err(MyErr::InvalidRecord)?
+    }
+    let klen = read_u32(&head[2..6]) as usize;
+    let vlen = read_u32(&head[6..10]) as usize;
+    let expiry_len = if head[1] & FLAG_EXPIRES != 0 {
+        EXPIRY_LEN
+    } else {
+        0
+    };
+    Ok(HEADER_LEN + expiry_len + klen + vlen)
+}
+
+fn checksum(rec: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&rec[..CRC_OFFSET]);
+    hasher.update(&rec[HEADER_LEN..]);
+    hasher.finalize()
+}
+
+fn is_legacy(head: &[u8]) -> bool {
+    head.first().is_some_and(|b| b & BINARY_MARK == 0)
+}
+
+fn read_u32(b: &[u8]) -> u32 {
+    let mut n = [0; 4];
+    n.copy_from_slice(b);
+    u32::from_be_bytes(n)
+}
@@ -1,40 +1,257 @@
-use crate::{KvsEngine, MyErr, Result};
+use super::{expires_at, now_millis};
+use crate::{BatchOp, KvsEngine, MyErr, Result, Transaction, WriteBatch};
 use async_trait::async_trait;
-use sled::{self, Db};
+use failure::err_msg;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree, UnabortableTransactionError,
+};
+use sled::{self, Batch, Db, Iter, Transactional, Tree};
 use std::path::PathBuf;
+use std::result;
+use std::time::Duration;
+
+/// Tree of expiries, keyed like the default tree
+const TTL_TREE: &str = "ttl";
+
+type TxResult<T> = ConflictableTransactionResult<T, sled::Error>;
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
     db: Db,
+    ttl: Tree,
 }
 
 impl SledKvsEngine {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let eng = SledKvsEngine {
-            db: sled::open(path.into())?,
-        };
-        Ok(eng)
+        let db = sled::open(path.into())?;
+        let ttl = db.open_tree(TTL_TREE)?;
+        Ok(SledKvsEngine { db, ttl })
+    }
+    /// Whether `key` is expired at `now`
+    fn is_expired(&self, key: &[u8], now: u64) -> Result<bool> {
+        Ok(self.ttl.get(key)?.is_some_and(|t| decode_expiry(&t) <= now))
+    }
+    /// Remove `key` if it is still expired, expired values are dropped lazily
+    fn expire(&self, key: &[u8]) -> Result<()> {
+        let now = now_millis();
+        (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<()> {
+            if ttl.get(key)?.is_some_and(|t| decode_expiry(&t) <= now) {
+                db.remove(key)?;
+                ttl.remove(key)?;
+            }
+            Ok(())
+        })?;
+        Ok(())
+    }
+    fn collect_pairs(&self, iter: Iter, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let now = now_millis();
+        let mut pairs = Vec::new();
+        for kv in iter {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (k, v) = kv?;
+            if !self.is_expired(&k, now)? {
+                pairs.push((k.to_vec(), v.to_vec()));
+            }
+        }
+        Ok(pairs)
     }
 }
 
 #[async_trait]
 impl KvsEngine for SledKvsEngine {
-    async fn set(self, key: String, val: String) -> Result<()> {
-        self.db.insert(key, val.as_bytes())?;
+    async fn set(self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<()> {
+            db.insert(key.as_slice(), val.as_slice())?;
+            ttl.remove(key.as_slice())?;
+            Ok(())
+        })?;
         self.db.flush()?;
         Ok(())
     }
-    async fn get(self, key: String) -> Result<Option<String>> {
-        let opt_iv = self.db.get(key)?;
-        if let Some(iv) = opt_iv {
-            Ok(Some(String::from_utf8(iv.to_vec())?))
-        } else {
-            Ok(None)
+    async fn set_with_ttl(self, key: Vec<u8>, val: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expiry = expires_at(ttl).to_be_bytes();
+        (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<()> {
+            db.insert(key.as_slice(), val.as_slice())?;
+            ttl.insert(key.as_slice(), &expiry[..])?;
+            Ok(())
+        })?;
+        self.db.flush()?;
+        Ok(())
+    }
+    async fn get(self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let Some(iv) = self.db.get(&key)? else {
+            return Ok(None);
+        };
+        if self.is_expired(&key, now_millis())? {
+            self.expire(&key)?;
+            return Ok(None);
         }
+        Ok(Some(iv.to_vec()))
     }
-    async fn remove(self, key: String) -> Result<()> {
-        self.db.remove(key)?.ok_or(MyErr::KeyNotFound)?;
+    async fn remove(self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        let found = (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<bool> {
+            let expiry = ttl.remove(key.as_slice())?;
+            let found = db.remove(key.as_slice())?.is_some();
+            Ok(found && expiry.is_none_or(|t| decode_expiry(&t) > now))
+        })?;
+        if !found {
+            Err(MyErr::KeyNotFound)?
+        }
+        self.db.flush()?;
+        Ok(())
+    }
+    /// Like `Tree::compare_and_swap`, in a transaction with the expiry tree
+    /// so that an expired value compares as absent
+    async fn compare_and_swap(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let now = now_millis();
+        let swapped = (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<bool> {
+            let expiry = ttl.get(key.as_slice())?;
+            let current = db
+                .get(key.as_slice())?
+                .filter(|_| expiry.as_ref().is_none_or(|t| decode_expiry(t) > now));
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(val) => db.insert(key.as_slice(), val.as_slice())?,
+                None => db.remove(key.as_slice())?,
+            };
+            ttl.remove(key.as_slice())?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.db.flush()?;
+        }
+        Ok(swapped)
+    }
+    /// As a `sled::Batch`, applied with the removal of the expiries of its keys
+    async fn apply_batch(self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let (mut writes, mut expiries) = (Batch::default(), Batch::default());
+        for op in batch {
+            match op {
+                BatchOp::Set(key, val) => {
+                    expiries.remove(key.as_slice());
+                    writes.insert(key, val);
+                }
+                BatchOp::Remove(key) => {
+                    expiries.remove(key.as_slice());
+                    writes.remove(key);
+                }
+            }
+        }
+        (&*self.db, &self.ttl).transaction(|(db, ttl)| -> TxResult<()> {
+            db.apply_batch(&writes)?;
+            ttl.apply_batch(&expiries)?;
+            Ok(())
+        })?;
         self.db.flush()?;
         Ok(())
     }
+    /// Delegated to a sled transaction over the trees of values and expiries,
+    /// which runs `f` again on conflict
+    async fn transaction<F, T>(self, f: F) -> Result<T>
+    where
+        F: Fn(&mut dyn Transaction) -> Result<T> + Send + Sync + 'static,
+        T: Send + 'static,
+    {
+        let rlt = (&*self.db, &self.ttl).transaction(
+            |(db, ttl)| -> ConflictableTransactionResult<T, failure::Error> {
+                let mut tx = SledTransaction {
+                    db,
+                    ttl,
+                    now: now_millis(),
+                    err: None,
+                };
+                let rlt = f(&mut tx);
+                // a conflict must reach sled even if `f` swallowed it
+                match tx.err {
+                    Some(err) => Err(err.into()),
+                    None => rlt.map_err(ConflictableTransactionError::Abort),
+                }
+            },
+        );
+        let val = rlt.map_err(|err| match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => err.into(),
+        })?;
+        self.db.flush()?;
+        Ok(val)
+    }
+    async fn scan(
+        self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        match end {
+            Some(end) if start >= end => Ok(Vec::new()),
+            Some(end) => self.collect_pairs(self.db.range(start..end), limit),
+            None => self.collect_pairs(self.db.range(start..), limit),
+        }
+    }
+    async fn scan_prefix(self, prefix: Vec<u8>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.collect_pairs(self.db.scan_prefix(prefix), limit)
+    }
+}
+
+/// Transaction on `SledKvsEngine`, reading and writing both trees
+struct SledTransaction<'a> {
+    db: &'a TransactionalTree,
+    ttl: &'a TransactionalTree,
+    now: u64,
+    /// Conflict or storage error met, handed back to sled
+    err: Option<UnabortableTransactionError>,
+}
+
+impl SledTransaction<'_> {
+    fn check<T>(&mut self, rlt: result::Result<T, UnabortableTransactionError>) -> Result<T> {
+        rlt.map_err(|err| {
+            let msg = err_msg(format!("transaction failed: {:?}", err));
+            self.err = Some(err);
+            msg
+        })
+    }
+}
+
+impl Transaction for SledTransaction<'_> {
+    /// An expired value is absent
+    fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let expiry = self.ttl.get(key.as_slice());
+        let expiry = self.check(expiry)?;
+        if expiry.is_some_and(|t| decode_expiry(&t) <= self.now) {
+            return Ok(None);
+        }
+        let val = self.db.get(key.as_slice());
+        Ok(self.check(val)?.map(|v| v.to_vec()))
+    }
+    fn set(&mut self, key: Vec<u8>, val: Vec<u8>) -> Result<()> {
+        let rlt = self.db.insert(key.as_slice(), val);
+        self.check(rlt)?;
+        let rlt = self.ttl.remove(key);
+        self.check(rlt).map(|_| ())
+    }
+    fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        let rlt = self.db.remove(key.as_slice());
+        self.check(rlt)?;
+        let rlt = self.ttl.remove(key);
+        self.check(rlt).map(|_| ())
+    }
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut n = [0; 8];
+    n.copy_from_slice(bytes);
+    u64::from_be_bytes(n)
 }
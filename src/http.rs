@@ -0,0 +1,224 @@
+//! HTTP/JSON gateway over any `KvsEngine`:
+//! - `GET /keys/{key}` replies the value as body, or 404
+//! - `PUT /keys/{key}` stores the request body as value
+//! - `DELETE /keys/{key}` replies 404 if the key is not found
+//! - `GET /keys?prefix={prefix}&limit={limit}` replies pairs of keys starting
+//!   with `prefix` in key order, as a JSON array of `{"key": .., "value": ..}`.
+//!   At most 1000 pairs are replied, whatever `limit` is
+//! - `GET /health`
+//!
+//! Keys in paths and queries are percent-encoded bytes. In JSON, a key or
+//! value is a string if it is valid UTF-8, otherwise an array of bytes.
+//! Errors are replied as `{"error": message}`.
+//!
+//! Keys and values are bounded by the limits of `ServerOptions`: a key over
+//! its limit is replied 414, a body over its limit 413.
+use crate::protocol;
+use crate::server::ServerOptions;
+use crate::{KvsEngine, MyErr, Result};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::TcpListener;
+use std::result;
+use tracing::{debug, error, info};
+
+/// Most pairs replied by a scan, also the number without `limit`
+const SCAN_LIMIT: usize = 1000;
+
+/// Serve `engine` on `addr` until `shutdown` resolves, then requests in flight
+/// are answered before returning. Only the key and value limits of `opts`
+/// apply.
+pub async fn run<E, S>(addr: &str, engine: E, opts: ServerOptions, shutdown: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: Future<Output = ()>,
+{
+    info!("http gateway is running on {}", addr);
+    let listener = TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let make_svc = make_service_fn(move |_| {
+        let eng = engine.clone();
+        let opts = opts.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let (eng, opts) = (eng.clone(), opts.clone());
+                async move { Ok::<_, Infallible>(handle(req, eng, &opts).await) }
+            }))
+        }
+    });
+    Server::from_tcp(listener)?
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    info!("http gateway stopped");
+    Ok(())
+}
+
+async fn handle<E: KvsEngine>(req: Request<Body>, eng: E, opts: &ServerOptions) -> Response<Body> {
+    debug!("{} {}", req.method(), req.uri());
+    let path = req.uri().path().to_owned();
+    if path == "/health" {
+        return match *req.method() {
+            Method::GET => json(StatusCode::OK, &serde_json::json!({"status": "ok"})),
+            _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        };
+    }
+    if path == "/keys" {
+        return match *req.method() {
+            Method::GET => scan(req, eng, opts).await,
+            _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+        };
+    }
+    let key = match path.strip_prefix("/keys/") {
+        Some(key) => percent_decode_str(key).collect::<Vec<u8>>(),
+        None => return error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+    if let Err(e) = protocol::check_len("key", &key, opts.max_key_len) {
+        return error_response(StatusCode::URI_TOO_LONG, &e.to_string());
+    }
+    match *req.method() {
+        Method::GET => match eng.get(key).await {
+            Ok(Some(val)) => Response::builder()
+                .header(header::CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(val))
+                .expect("valid response"),
+            Ok(None) => error_response(StatusCode::NOT_FOUND, &MyErr::KeyNotFound.to_string()),
+            Err(e) => engine_error(e),
+        },
+        Method::PUT => {
+            let val = match read_body(req.into_body(), opts.max_val_len).await {
+                Ok(val) => val,
+                Err(resp) => return resp,
+            };
+            match eng.set(key, val).await {
+                Ok(()) => no_content(),
+                Err(e) => engine_error(e),
+            }
+        }
+        Method::DELETE => match eng.remove(key).await {
+            Ok(()) => no_content(),
+            Err(e) => engine_error(e),
+        },
+        _ => error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"),
+    }
+}
+
+/// Read a body of at most `max_len` bytes, replying 413 to a longer one
+/// without reading it all
+async fn read_body(mut body: Body, max_len: usize) -> result::Result<Vec<u8>, Response<Body>> {
+    let too_large = |len: u64| {
+        let e = MyErr::TooLarge("value", len, max_len as u64);
+        error_response(StatusCode::PAYLOAD_TOO_LARGE, &e.to_string())
+    };
+    // the length announced by `Content-Length`, if any
+    let announced = body.size_hint().exact();
+    if let Some(len) = announced.filter(|&len| len > max_len as u64) {
+        return Err(too_large(len));
+    }
+    let mut buf = Vec::with_capacity(announced.unwrap_or(0) as usize);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| error_response(StatusCode::BAD_REQUEST, &e.to_string()))?;
+        if buf.len() + chunk.len() > max_len {
+            return Err(too_large((buf.len() + chunk.len()) as u64));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf)
+}
+
+async fn scan<E: KvsEngine>(req: Request<Body>, eng: E, opts: &ServerOptions) -> Response<Body> {
+    let query = req.uri().query().unwrap_or_default();
+    let prefix = query_param(query, "prefix").unwrap_or_default();
+    if let Err(e) = protocol::check_len("key", &prefix, opts.max_key_len) {
+        return error_response(StatusCode::URI_TOO_LONG, &e.to_string());
+    }
+    let limit = match query_param(query, "limit") {
+        Some(limit) => match std::str::from_utf8(&limit)
+            .ok()
+            .and_then(|l| l.parse().ok())
+        {
+            Some(limit) => SCAN_LIMIT.min(limit),
+            None => return error_response(StatusCode::BAD_REQUEST, "invalid limit"),
+        },
+        None => SCAN_LIMIT,
+    };
+    match eng.scan_prefix(prefix, limit).await {
+        Ok(pairs) => {
+            let pairs = pairs
+                .into_iter()
+                .map(|(key, value)| Pair {
+                    key: JsonBytes::from(key),
+                    value: JsonBytes::from(value),
+                })
+                .collect::<Vec<_>>();
+            json(StatusCode::OK, &pairs)
+        }
+        Err(e) => engine_error(e),
+    }
+}
+
+#[derive(Serialize)]
+struct Pair {
+    key: JsonBytes,
+    value: JsonBytes,
+}
+
+/// Bytes as a JSON string if valid UTF-8, otherwise as an array
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonBytes {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Vec<u8>> for JsonBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(s) => JsonBytes::Str(s),
+            Err(e) => JsonBytes::Bytes(e.into_bytes()),
+        }
+    }
+}
+
+/// Percent-decoded value of the first parameter `name` of a query
+fn query_param(query: &str, name: &str) -> Option<Vec<u8>> {
+    query.split('&').find_map(|param| {
+        let (k, v) = param.split_once('=').unwrap_or((param, ""));
+        let v = v.replace('+', " ");
+        (k == name).then(|| percent_decode_str(&v).collect())
+    })
+}
+
+fn engine_error(e: failure::Error) -> Response<Body> {
+    match e.downcast_ref::<MyErr>() {
+        Some(MyErr::KeyNotFound) => error_response(StatusCode::NOT_FOUND, &e.to_string()),
+        _ => {
+            error!("http: err={}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string())
+        }
+    }
+}
+
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_vec(body).expect("serializable")))
+        .expect("valid response")
+}
+
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "error": msg }))
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .expect("valid response")
+}
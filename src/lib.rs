@@ -2,12 +2,15 @@
 //! This is key-value store lib
 pub mod client;
 pub mod engine;
+pub mod http;
 pub mod protocol;
+pub mod resp;
 pub mod server;
 pub mod thread_pool;
 
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
-use failure;
+pub use engine::{
+    BatchOp, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy, Transaction, WriteBatch,
+};
 use std::error::Error;
 use std::fmt;
 use std::result;
@@ -19,6 +22,15 @@ pub enum MyErr {
     KeyNotFound,
     ErrExtension,
     WrongEngine,
+    InvalidRecord,
+    Corrupted(u32, u64),
+    CorruptedManifest,
+    InvalidFrame,
+    UnknownOp(u8),
+    /// What is too large, its length and the limit
+    TooLarge(&'static str, u64, u64),
+    /// Attempts of a transaction which all conflicted
+    Conflict(u32),
 }
 
 impl fmt::Display for MyErr {
@@ -27,6 +39,23 @@ impl fmt::Display for MyErr {
             MyErr::KeyNotFound => write!(f, "Key not found"),
             MyErr::ErrExtension => write!(f, "Unexpected file extension"),
             MyErr::WrongEngine => write!(f, "Wrong engine detected"),
+            MyErr::InvalidRecord => write!(f, "Invalid record format"),
+            MyErr::Corrupted(file, offset) => {
+                write!(
+                    f,
+                    "Corrupted record in segment {} at offset {}",
+                    file, offset
+                )
+            }
+            MyErr::CorruptedManifest => write!(f, "Corrupted manifest"),
+            MyErr::InvalidFrame => write!(f, "Invalid protocol frame"),
+            MyErr::UnknownOp(op) => write!(f, "Unknown operation {:#04x}", op),
+            MyErr::TooLarge(what, len, max) => {
+                write!(f, "{} of {} bytes exceeds the limit of {}", what, len, max)
+            }
+            MyErr::Conflict(attempts) => {
+                write!(f, "Transaction conflicted in all {} attempts", attempts)
+            }
         }
     }
 }
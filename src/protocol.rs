@@ -1,8 +1,541 @@
-pub const OP_SET: u8 = '+' as u8;
-pub const OP_RM: u8 = '-' as u8;
-pub const OP_GET: u8 = '?' as u8;
-// pub const OP_CLOSE: u8 = 'C' as u8;
-
-pub const GET_VAL: u8 = 'v' as u8;
-pub const GET_NIL: u8 = 'n' as u8;
-pub const GET_ERR: u8 = 'e' as u8;
+//! Two protocols are served side by side, told apart by the first byte a
+//! client sends.
+//!
+//! A connection serves requests until the client closes it or sends
+//! `OP_CLOSE`, or it stays idle for too long. Requests may be pipelined, that
+//! is sent without waiting for responses, which come in the order of requests.
+//!
+//! Legacy protocol: a request is an op followed by its fields, a response is
+//! a status followed by its fields. Keys, values and messages are
+//! length-prefixed fields:
+//! | len: u32 | bytes |
+//!
+//! Protocol v2: a client starts with `| HANDSHAKE | version: u8 |`, which the
+//! server echoes with the version it speaks. Then requests and responses are
+//! frames, a response carries the id of its request:
+//! | len: u32 | id: u32 | code: u8 | body |
+//! `len` covers `id`, `code` and `body`. `code` is the `OP_*` of a request or
+//! the `Status` of a response. Bodies are made of length-prefixed fields too.
+//!
+//! Integers are big-endian.
+//!
+//! Requests the server refuses, such as an unknown op or a key, value or
+//! frame over its limit, are replied with an error. A legacy connection is
+//! closed after it, since the rest of the request can not be skipped. So is a
+//! v2 connection after a frame too large, whose response has id 0.
+//!
+//! A server at its connection limit refuses a connection by answering its
+//! first request with an error, then closes it. A v2 handshake is refused by
+//! `| HANDSHAKE | VERSION_REFUSED | message |`, so is an unsupported one.
+use crate::{BatchOp, MyErr, Result, WriteBatch};
+use std::io::{self, Read};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// `| key | val |`
+pub const OP_SET: u8 = b'+';
+/// `| key | val | ttl_ms: u64 |`, the value expires `ttl_ms` milliseconds later
+pub const OP_SET_TTL: u8 = b'~';
+/// `| key |`
+pub const OP_RM: u8 = b'-';
+/// `| key | expected | new |`, swap the value if it is `expected`. Both are
+/// optional fields, `| 0: u8 |` for absent or `| 1: u8 | field |`
+pub const OP_CAS: u8 = b'=';
+/// `| count: u32 | write |*`, writes applied in order and atomically. A write
+/// is `| OP_SET | key | val |` or `| OP_RM | key |`
+pub const OP_BATCH: u8 = b'&';
+/// `| key |`
+pub const OP_GET: u8 = b'?';
+/// `| start | end | limit: u64 |`, an empty `end` is unbounded
+pub const OP_SCAN: u8 = b'[';
+/// `| prefix | limit: u64 |`
+pub const OP_SCAN_PREFIX: u8 = b'*';
+/// No fields, the server replies then closes the connection
+pub const OP_CLOSE: u8 = b'C';
+/// No fields, replied with `RES_OK` to check liveness
+pub const OP_PING: u8 = b'P';
+
+/// Succeeded without value
+pub const RES_OK: u8 = b'o';
+/// `| val |`
+pub const GET_VAL: u8 = b'v';
+/// Key not found
+pub const GET_NIL: u8 = b'n';
+/// `| message |`
+pub const GET_ERR: u8 = b'e';
+/// Not swapped, the value is not the expected one
+pub const RES_CONFLICT: u8 = b'x';
+
+/// Scan results are streamed as `| SCAN_ITEM | key | val |` items, ended by
+/// `SCAN_END` or by an error
+pub const SCAN_ITEM: u8 = b'k';
+pub const SCAN_END: u8 = b'.';
+
+/// Starts a v2 session, its first byte is never a legacy op
+pub const HANDSHAKE: [u8; 4] = *b"\0KVS";
+pub const VERSION: u8 = 2;
+/// Version answered to a handshake refused by the server
+pub const VERSION_REFUSED: u8 = 0;
+/// Length of `id` and `code` of a frame
+const FRAME_HEADER_LEN: usize = 5;
+
+/// Longest key accepted by the server by default
+pub const MAX_KEY_LEN: usize = 64 * 1024;
+/// Longest value accepted by the server by default
+pub const MAX_VAL_LEN: usize = 64 * 1024 * 1024;
+/// Longest request accepted by the server by default, enough for a `set` of
+/// the longest key and value
+pub const MAX_REQUEST_LEN: usize = MAX_KEY_LEN + MAX_VAL_LEN + 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        val: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: u64,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        limit: u64,
+    },
+    Close,
+    Ping,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub id: u32,
+    pub cmd: Command,
+}
+
+/// Status code of a v2 response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    /// Succeeded without value
+    Ok = 0,
+    /// `| val |`
+    Value = 1,
+    NotFound = 2,
+    /// `| count: u32 | key | val | ... |`, a page of scan results, more
+    /// responses follow until `Ok` or an error
+    Partial = 3,
+    /// `| message |`
+    BadRequest = 4,
+    /// `| message |`
+    Internal = 5,
+    /// `| message |`, the server refused the session, which may be retried
+    /// later. It is not the status of any response
+    Unavailable = 6,
+    /// Not swapped, the value is not the expected one
+    Conflict = 7,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Done,
+    Value(Vec<u8>),
+    NotFound,
+    Pairs(Vec<(Vec<u8>, Vec<u8>)>),
+    Conflict,
+    /// `status` is either `BadRequest` or `Internal`
+    Error(Status, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: u32,
+    pub reply: Reply,
+}
+
+impl TryFrom<u8> for Status {
+    type Error = failure::Error;
+    fn try_from(code: u8) -> Result<Self> {
+        Ok(match code {
+            0 => Status::Ok,
+            1 => Status::Value,
+            2 => Status::NotFound,
+            3 => Status::Partial,
+            4 => Status::BadRequest,
+            5 => Status::Internal,
+            6 => Status::Unavailable,
+            7 => Status::Conflict,
+            _ => Err(MyErr::InvalidFrame)?,
+        })
+    }
+}
+
+impl Request {
+    /// Encode into a whole frame
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let op = match &self.cmd {
+            Command::Get { key } => {
+                put_field(&mut body, key);
+                OP_GET
+            }
+            Command::Set { key, val } => {
+                put_field(&mut body, key);
+                put_field(&mut body, val);
+                OP_SET
+            }
+            Command::SetWithTtl { key, val, ttl } => {
+                put_field(&mut body, key);
+                put_field(&mut body, val);
+                body.extend_from_slice(&ttl_millis(*ttl).to_be_bytes());
+                OP_SET_TTL
+            }
+            Command::Remove { key } => {
+                put_field(&mut body, key);
+                OP_RM
+            }
+            Command::CompareAndSwap { key, expected, new } => {
+                put_field(&mut body, key);
+                put_opt_field(&mut body, expected.as_deref());
+                put_opt_field(&mut body, new.as_deref());
+                OP_CAS
+            }
+            Command::Batch { batch } => {
+                put_batch(&mut body, batch);
+                OP_BATCH
+            }
+            Command::Scan { start, end, limit } => {
+                put_field(&mut body, start);
+                put_field(&mut body, end.as_deref().unwrap_or_default());
+                body.extend_from_slice(&limit.to_be_bytes());
+                OP_SCAN
+            }
+            Command::ScanPrefix { prefix, limit } => {
+                put_field(&mut body, prefix);
+                body.extend_from_slice(&limit.to_be_bytes());
+                OP_SCAN_PREFIX
+            }
+            Command::Close => OP_CLOSE,
+            Command::Ping => OP_PING,
+        };
+        encode_frame(self.id, op, &body)
+    }
+    /// Decode from a frame read by `read_frame`
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let (id, op, mut body) = split_frame(frame)?;
+        let cmd = match op {
+            OP_GET => Command::Get {
+                key: get_field(&mut body)?,
+            },
+            OP_SET => Command::Set {
+                key: get_field(&mut body)?,
+                val: get_field(&mut body)?,
+            },
+            OP_SET_TTL => Command::SetWithTtl {
+                key: get_field(&mut body)?,
+                val: get_field(&mut body)?,
+                ttl: Duration::from_millis(get_u64(&mut body)?),
+            },
+            OP_RM => Command::Remove {
+                key: get_field(&mut body)?,
+            },
+            OP_CAS => Command::CompareAndSwap {
+                key: get_field(&mut body)?,
+                expected: get_opt_field(&mut body)?,
+                new: get_opt_field(&mut body)?,
+            },
+            OP_BATCH => Command::Batch {
+                batch: get_batch(&mut body)?,
+            },
+            OP_SCAN => Command::Scan {
+                start: get_field(&mut body)?,
+                end: Some(get_field(&mut body)?).filter(|end| !end.is_empty()),
+                limit: get_u64(&mut body)?,
+            },
+            OP_SCAN_PREFIX => Command::ScanPrefix {
+                prefix: get_field(&mut body)?,
+                limit: get_u64(&mut body)?,
+            },
+            OP_CLOSE => Command::Close,
+            OP_PING => Command::Ping,
+            _ => Err(MyErr::InvalidFrame)?,
+        };
+        if !body.is_empty() {
+            Err(MyErr::InvalidFrame)?
+        }
+        Ok(Request { id, cmd })
+    }
+}
+
+impl Command {
+    /// Check lengths of keys and values against their limits
+    pub fn check_len(&self, max_key_len: usize, max_val_len: usize) -> Result<()> {
+        let (key, val) = match self {
+            Command::Get { key } | Command::Remove { key } => (key, None),
+            Command::Set { key, val } | Command::SetWithTtl { key, val, .. } => (key, Some(val)),
+            Command::CompareAndSwap { key, expected, new } => {
+                let expected = expected.as_deref().unwrap_or_default();
+                check_len("value", expected, max_val_len)?;
+                (key, new.as_ref())
+            }
+            Command::Batch { batch } => {
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Set(key, val) => {
+                            check_len("key", key, max_key_len)?;
+                            check_len("value", val, max_val_len)?;
+                        }
+                        BatchOp::Remove(key) => check_len("key", key, max_key_len)?,
+                    }
+                }
+                return Ok(());
+            }
+            Command::Scan { start, end, .. } => {
+                check_len("key", end.as_deref().unwrap_or_default(), max_key_len)?;
+                (start, None)
+            }
+            Command::ScanPrefix { prefix, .. } => (prefix, None),
+            Command::Close | Command::Ping => return Ok(()),
+        };
+        check_len("key", key, max_key_len)?;
+        check_len("value", val.map_or(&[][..], |v| v), max_val_len)
+    }
+}
+
+/// `ttl` in whole milliseconds as sent on wire
+pub fn ttl_millis(ttl: Duration) -> u64 {
+    ttl.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Fail with `MyErr::TooLarge` if `buf` is longer than `max`
+pub fn check_len(what: &'static str, buf: &[u8], max: usize) -> Result<()> {
+    if buf.len() > max {
+        Err(MyErr::TooLarge(what, buf.len() as u64, max as u64))?
+    }
+    Ok(())
+}
+
+impl Reply {
+    pub fn status(&self) -> Status {
+        match self {
+            Reply::Done => Status::Ok,
+            Reply::Value(_) => Status::Value,
+            Reply::NotFound => Status::NotFound,
+            Reply::Pairs(_) => Status::Partial,
+            Reply::Conflict => Status::Conflict,
+            Reply::Error(status, _) => *status,
+        }
+    }
+}
+
+impl Response {
+    /// Encode into a whole frame
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match &self.reply {
+            Reply::Done | Reply::NotFound | Reply::Conflict => {}
+            Reply::Value(val) => put_field(&mut body, val),
+            Reply::Pairs(pairs) => {
+                body.extend_from_slice(&(pairs.len() as u32).to_be_bytes());
+                for (k, v) in pairs {
+                    put_field(&mut body, k);
+                    put_field(&mut body, v);
+                }
+            }
+            Reply::Error(_, msg) => put_field(&mut body, msg.as_bytes()),
+        }
+        encode_frame(self.id, self.reply.status() as u8, &body)
+    }
+    /// Decode from a frame read by `read_frame`
+    pub fn decode(frame: &[u8]) -> Result<Self> {
+        let (id, code, mut body) = split_frame(frame)?;
+        let reply = match Status::try_from(code)? {
+            Status::Ok => Reply::Done,
+            Status::Value => Reply::Value(get_field(&mut body)?),
+            Status::NotFound => Reply::NotFound,
+            Status::Conflict => Reply::Conflict,
+            Status::Partial => {
+                let n = get_u32(&mut body)?;
+                let mut pairs = Vec::new();
+                for _ in 0..n {
+                    pairs.push((get_field(&mut body)?, get_field(&mut body)?));
+                }
+                Reply::Pairs(pairs)
+            }
+            status @ (Status::BadRequest | Status::Internal | Status::Unavailable) => {
+                let msg = get_field(&mut body)?;
+                Reply::Error(status, String::from_utf8_lossy(&msg).into_owned())
+            }
+        };
+        if !body.is_empty() {
+            Err(MyErr::InvalidFrame)?
+        }
+        Ok(Response { id, reply })
+    }
+}
+
+/// Read a frame without its length prefix, returns `None` if the peer closed
+/// the connection between frames
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let mut frame = vec![0; u32::from_be_bytes(len) as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+/// Take a whole frame without its length prefix from the front of `buf`,
+/// returns `None` if `buf` does not hold one yet
+pub fn take_frame(buf: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let len = u32::from_be_bytes(buf.get(..4)?.try_into().expect("slice of 4 bytes")) as usize;
+    let frame = buf.get(4..4 + len)?.to_vec();
+    buf.drain(..4 + len);
+    Some(frame)
+}
+
+/// Async version of `read_frame`, a frame longer than `max_len` fails with
+/// `MyErr::TooLarge` before it is read
+pub async fn read_frame_async<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    if len as usize > max_len {
+        Err(MyErr::TooLarge("frame", len.into(), max_len as u64))?
+    }
+    let mut frame = vec![0; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+fn encode_frame(id: u32, code: u8, body: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + FRAME_HEADER_LEN + body.len());
+    buf.extend_from_slice(&((FRAME_HEADER_LEN + body.len()) as u32).to_be_bytes());
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.push(code);
+    buf.extend_from_slice(body);
+    buf
+}
+
+fn split_frame(frame: &[u8]) -> Result<(u32, u8, &[u8])> {
+    if frame.len() < FRAME_HEADER_LEN {
+        Err(MyErr::InvalidFrame)?
+    }
+    let id = u32::from_be_bytes(frame[0..4].try_into()?);
+    Ok((id, frame[4], &frame[FRAME_HEADER_LEN..]))
+}
+
+fn put_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    buf.extend_from_slice(field);
+}
+
+fn put_opt_field(buf: &mut Vec<u8>, field: Option<&[u8]>) {
+    match field {
+        Some(field) => {
+            buf.push(1);
+            put_field(buf, field);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn get_opt_field(body: &mut &[u8]) -> Result<Option<Vec<u8>>> {
+    let (&present, rest) = body.split_first().ok_or(MyErr::InvalidFrame)?;
+    *body = rest;
+    match present {
+        0 => Ok(None),
+        1 => Ok(Some(get_field(body)?)),
+        _ => Err(MyErr::InvalidFrame)?,
+    }
+}
+
+/// Encode writes of `batch` as the body of `OP_BATCH`
+fn put_batch(buf: &mut Vec<u8>, batch: &WriteBatch) {
+    buf.extend_from_slice(&(batch.len() as u32).to_be_bytes());
+    for op in batch.ops() {
+        match op {
+            BatchOp::Set(key, val) => {
+                buf.push(OP_SET);
+                put_field(buf, key);
+                put_field(buf, val);
+            }
+            BatchOp::Remove(key) => {
+                buf.push(OP_RM);
+                put_field(buf, key);
+            }
+        }
+    }
+}
+
+fn get_batch(body: &mut &[u8]) -> Result<WriteBatch> {
+    let count = get_u32(body)?;
+    let mut batch = WriteBatch::new();
+    for _ in 0..count {
+        let (&op, rest) = body.split_first().ok_or(MyErr::InvalidFrame)?;
+        *body = rest;
+        match op {
+            OP_SET => {
+                let key = get_field(body)?;
+                batch.set(key, get_field(body)?);
+            }
+            OP_RM => batch.remove(get_field(body)?),
+            _ => Err(MyErr::InvalidFrame)?,
+        }
+    }
+    Ok(batch)
+}
+
+fn get_field(body: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = get_u32(body)? as usize;
+    if body.len() < len {
+        Err(MyErr::InvalidFrame)?
+    }
+    let (field, rest) = body.split_at(len);
+    *body = rest;
+    Ok(field.to_vec())
+}
+
+fn get_u32(body: &mut &[u8]) -> Result<u32> {
+    if body.len() < 4 {
+        Err(MyErr::InvalidFrame)?
+    }
+    let (n, rest) = body.split_at(4);
+    *body = rest;
+    Ok(u32::from_be_bytes(n.try_into()?))
+}
+
+fn get_u64(body: &mut &[u8]) -> Result<u64> {
+    if body.len() < 8 {
+        Err(MyErr::InvalidFrame)?
+    }
+    let (n, rest) = body.split_at(8);
+    *body = rest;
+    Ok(u64::from_be_bytes(n.try_into()?))
+}
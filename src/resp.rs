@@ -0,0 +1,190 @@
+//! RESP2, the protocol of Redis, so that Redis tools and client libraries can
+//! talk to kvs-server.
+//!
+//! A command is an array of bulk strings:
+//! `*<n>\r\n` followed by n of `$<len>\r\n<bytes>\r\n`
+//! or an inline command, a line of arguments separated by spaces.
+//! A reply is any of `Value`.
+use crate::Result;
+use failure::err_msg;
+use std::io::BufRead;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// Longest line of a header or an inline command
+const MAX_LINE: u64 = 64 * 1024;
+/// Most arguments of a command
+const MAX_ARGS: usize = 1024 * 1024;
+/// Longest bulk string
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// `+<string>\r\n`
+    Simple(String),
+    /// `-<message>\r\n`
+    Error(String),
+    /// `:<integer>\r\n`
+    Integer(i64),
+    /// `$<len>\r\n<bytes>\r\n`
+    Bulk(Vec<u8>),
+    /// `$-1\r\n`, a null array `*-1\r\n` is read as `Nil` too
+    Nil,
+    /// `*<n>\r\n` followed by n values
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_to(&mut buf);
+        buf
+    }
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Simple(s) => put_line(buf, b'+', s),
+            Value::Error(msg) => put_line(buf, b'-', msg),
+            Value::Integer(n) => put_line(buf, b':', &n.to_string()),
+            Value::Bulk(bytes) => {
+                put_line(buf, b'$', &bytes.len().to_string());
+                buf.extend_from_slice(bytes);
+                buf.extend_from_slice(b"\r\n");
+            }
+            Value::Nil => buf.extend_from_slice(b"$-1\r\n"),
+            Value::Array(values) => {
+                put_line(buf, b'*', &values.len().to_string());
+                for v in values {
+                    v.encode_to(buf);
+                }
+            }
+        }
+    }
+}
+
+/// Read a command as its arguments, returns `None` if the peer closed the
+/// connection between commands. An empty inline command has no argument.
+/// Arguments longer than `max_len` in total are refused before read
+pub async fn read_command<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|c| c.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+    let n = parse_len(&line[1..], MAX_ARGS)?;
+    let mut args = Vec::with_capacity(n.min(1024));
+    let mut total = 0;
+    for _ in 0..n {
+        let line = read_line(reader)
+            .await?
+            .ok_or_else(|| protocol_err("unexpected end of stream"))?;
+        if line.first() != Some(&b'$') {
+            let got = line
+                .first()
+                .map_or(String::new(), |&c| (c as char).to_string());
+            return Err(protocol_err(&format!("expected '$', got '{}'", got)));
+        }
+        let len = parse_len(&line[1..], MAX_BULK_LEN)?;
+        total += len;
+        if total > max_len {
+            return Err(protocol_err("too big request"));
+        }
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_err("bulk string not ended by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Read a reply, for clients
+pub fn read_value<R: BufRead>(reader: &mut R) -> Result<Value> {
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    if !line.ends_with(b"\r\n") {
+        return Err(protocol_err("unexpected end of stream"));
+    }
+    line.truncate(line.len() - 2);
+    let (&kind, rest) = line
+        .split_first()
+        .ok_or_else(|| protocol_err("empty line"))?;
+    let text = String::from_utf8_lossy(rest).into_owned();
+    Ok(match kind {
+        b'+' => Value::Simple(text),
+        b'-' => Value::Error(text),
+        b':' => Value::Integer(text.parse()?),
+        b'$' if rest.starts_with(b"-") => Value::Nil,
+        b'$' => {
+            let len = parse_len(rest, MAX_BULK_LEN)?;
+            let mut bulk = vec![0; len + 2];
+            reader.read_exact(&mut bulk)?;
+            if !bulk.ends_with(b"\r\n") {
+                return Err(protocol_err("bulk string not ended by CRLF"));
+            }
+            bulk.truncate(len);
+            Value::Bulk(bulk)
+        }
+        b'*' if rest.starts_with(b"-") => Value::Nil,
+        b'*' => {
+            let n = parse_len(rest, MAX_ARGS)?;
+            let values = (0..n).map(|_| read_value(reader)).collect::<Result<_>>()?;
+            Value::Array(values)
+        }
+        _ => return Err(protocol_err(&format!("unknown type '{}'", kind as char))),
+    })
+}
+
+/// Read a line without its line ending, returns `None` at the end of stream
+async fn read_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let n = (&mut *reader)
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if n as u64 == MAX_LINE {
+            return Err(protocol_err("too big request"));
+        }
+        return Err(protocol_err("unexpected end of stream"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8], max: usize) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .filter(|&len| len <= max)
+        .ok_or_else(|| protocol_err("invalid length"))
+}
+
+fn put_line(buf: &mut Vec<u8>, kind: u8, s: &str) {
+    buf.push(kind);
+    // a simple string or an error can not hold a line ending
+    buf.extend(
+        s.bytes()
+            .map(|c| if c == b'\r' || c == b'\n' { b' ' } else { c }),
+    );
+    buf.extend_from_slice(b"\r\n");
+}
+
+fn protocol_err(msg: &str) -> failure::Error {
+    err_msg(format!("Protocol error: {}", msg))
+}
@@ -1,93 +1,1186 @@
-use crate::protocol;
-use crate::{KvsEngine, Result};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use crate::protocol::{self, Command, Reply, Request, Response, Status};
+use crate::resp::{self, Value};
+use crate::{KvsEngine, MyErr, Result, WriteBatch};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::io::{AsyncWriteExt, BufReader, BufWriter, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
-use tracing::{debug, error, info};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Sleep};
+use tracing::{debug, error, info, info_span, warn, Instrument};
 
-static X: &[char] = &['\n', '\t', ' '];
+/// Number of pairs read from engine at a time when streaming a scan
+const SCAN_PAGE: usize = 128;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_CONNECTIONS: usize = 1024;
+/// Longest wait for the first request of a connection refused, or the rest
+/// of its requests after a refused handshake
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Pause of accepting after running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// `errno` of running out of file descriptors, in the process and the system
+const EMFILE: i32 = 24;
+const ENFILE: i32 = 23;
+/// Capacity of socket buffers, pipelined requests and their responses are
+/// read and written in chunks of this size
+const BUF_SIZE: usize = 64 * 1024;
+/// Keys examined by a RESP `SCAN` without `COUNT`
+const RESP_SCAN_COUNT: usize = 10;
+/// Cursors of RESP `SCAN` kept by a connection
+const RESP_MAX_CURSORS: usize = 64;
 
-pub async fn run<E: KvsEngine>(addr: &str, engine: E) -> Result<()> {
+/// Protocol spoken by a listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Legacy protocol and protocol v2, see `protocol`
+    Kvs,
+    /// RESP2 of Redis, see `resp`
+    Resp,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    idle_timeout: Duration,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    drain_timeout: Duration,
+    max_connections: usize,
+    pub(crate) max_key_len: usize,
+    pub(crate) max_val_len: usize,
+    max_request_len: usize,
+    protocol: Protocol,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            idle_timeout: IDLE_TIMEOUT,
+            read_timeout: READ_TIMEOUT,
+            write_timeout: WRITE_TIMEOUT,
+            drain_timeout: DRAIN_TIMEOUT,
+            max_connections: MAX_CONNECTIONS,
+            max_key_len: protocol::MAX_KEY_LEN,
+            max_val_len: protocol::MAX_VAL_LEN,
+            max_request_len: protocol::MAX_REQUEST_LEN,
+            protocol: Protocol::Kvs,
+        }
+    }
+}
+
+impl ServerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Close connections that send no request for this long
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+    /// Close connections stuck this long in the middle of receiving a request
+    pub fn read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = timeout;
+        self
+    }
+    /// Close connections stuck this long sending a response
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+    /// Most connections served at a time, others are refused with an error
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = max;
+        self
+    }
+    pub fn max_key_len(mut self, len: usize) -> Self {
+        self.max_key_len = len;
+        self
+    }
+    pub fn max_val_len(mut self, len: usize) -> Self {
+        self.max_val_len = len;
+        self
+    }
+    /// Longest request, a v2 frame or the arguments of a RESP command
+    pub fn max_request_len(mut self, len: usize) -> Self {
+        self.max_request_len = len;
+        self
+    }
+    /// Longest wait for connections to finish their requests on shutdown,
+    /// those still busy are dropped after it
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+}
+
+/// Serve `engine` on `addr` until `shutdown` resolves. Then no connection is
+/// accepted, and open ones are closed once their requests in flight are
+/// answered, within `drain_timeout`. The engine is dropped before returning.
+pub async fn run<E, S>(addr: &str, engine: E, opts: ServerOptions, shutdown: S) -> Result<()>
+where
+    E: KvsEngine,
+    S: Future<Output = ()>,
+{
     info!("kvs-server is running...");
     let listener = TcpListener::bind(addr).await?;
+    let (stop, stopping) = watch::channel(false);
+    let permits = Arc::new(Semaphore::new(opts.max_connections));
+    let mut conns = JoinSet::new();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, addr) = listener.accept().await?;
-        let eng = engine.clone();
-        debug!("connected socket {}", addr);
-        tokio::spawn(handler(stream, eng));
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!(error = %e, "failed to accept connection");
+                        // wait for descriptors to be freed rather than spin
+                        if matches!(e.raw_os_error(), Some(EMFILE | ENFILE)) {
+                            sleep(ACCEPT_BACKOFF).await;
+                        }
+                        continue;
+                    }
+                };
+                let eng = engine.clone();
+                let opts = opts.clone();
+                let stopping = stopping.clone();
+                let permit = permits.clone().try_acquire_owned();
+                debug!("connected socket {}", peer);
+                // events of a connection are tagged with its peer
+                let conn = async move {
+                    let _permit = match permit {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            warn!("refusing connection over the limit");
+                            if let Err(e) = refuse(stream, opts.protocol).await {
+                                debug!(error = %e, "failed to refuse connection");
+                            }
+                            return;
+                        }
+                    };
+                    let served = match opts.protocol {
+                        Protocol::Kvs => handler(stream, eng, opts, stopping).await,
+                        Protocol::Resp => resp_handler(stream, eng, opts, stopping).await,
+                    };
+                    if let Err(e) = served {
+                        warn!(error = %e, "connection failed");
+                    }
+                };
+                conns.spawn(conn.instrument(info_span!("conn", %peer)));
+            }
+            // reap closed connections
+            Some(joined) = conns.join_next(), if !conns.is_empty() => {
+                if let Err(e) = joined {
+                    error!(error = %e, "connection task panicked");
+                }
+            }
+            () = &mut shutdown => break,
+        }
+    }
+    drop(listener);
+    info!(
+        "kvs-server is shutting down, draining {} connections",
+        conns.len()
+    );
+    stop.send_replace(true);
+    let drain = async { while conns.join_next().await.is_some() {} };
+    if timeout(opts.drain_timeout, drain).await.is_err() {
+        error!("dropping {} connections not drained in time", conns.len());
+        conns.shutdown().await;
     }
+    drop(engine);
+    info!("kvs-server stopped");
+    Ok(())
 }
 
-pub async fn handler<E: KvsEngine>(mut stream: TcpStream, eng: E) -> Result<()> {
+/// Serve requests of a connection until it is closed
+pub async fn handler<E: KvsEngine>(
+    mut stream: TcpStream,
+    eng: E,
+    opts: ServerOptions,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<()> {
     let (reader, writer) = stream.split();
-    let mut reader = BufReader::with_capacity(1024, reader);
-    let mut writer = BufWriter::with_capacity(1024, writer);
-    match reader.read_u8().await? {
-        protocol::OP_SET => {
-            let mut key = String::new();
-            reader.read_line(&mut key).await?;
-            key = key.trim_matches(X).to_owned();
-            if key.len() == 0 {
-                writer.write_all("ErrNoKey\n".as_bytes()).await?;
-                return Ok(());
+    let mut reader = BufReader::with_capacity(BUF_SIZE, Timed::new(reader, opts.read_timeout));
+    let mut writer = BufWriter::with_capacity(BUF_SIZE, Timed::new(writer, opts.write_timeout));
+    let first = match read_op(&mut reader, opts.idle_timeout, &mut stopping).await? {
+        Some(op) => op,
+        None => return Ok(()),
+    };
+    if first == protocol::HANDSHAKE[0] {
+        let mut hello = [0; 4];
+        reader.read_exact(&mut hello).await?;
+        if hello[..3] != protocol::HANDSHAKE[1..] || hello[3] != protocol::VERSION {
+            warn!(?hello, "unsupported handshake");
+            let msg = if hello[..3] == protocol::HANDSHAKE[1..] {
+                format!("unsupported version {}", hello[3])
+            } else {
+                "invalid handshake".to_owned()
+            };
+            writer.write_all(&protocol::HANDSHAKE).await?;
+            writer.write_u8(protocol::VERSION_REFUSED).await?;
+            write_field(&mut writer, msg.as_bytes()).await?;
+            writer.flush().await?;
+            writer.shutdown().await?;
+            // closing with bytes unread would reset the connection, losing the reply
+            let mut sink = tokio::io::sink();
+            timeout(REFUSE_TIMEOUT, tokio::io::copy(&mut reader, &mut sink)).await??;
+            return Ok(());
+        }
+        writer.write_all(&protocol::HANDSHAKE).await?;
+        writer.write_u8(protocol::VERSION).await?;
+        writer.flush().await?;
+        loop {
+            if !next_request(&mut reader, opts.idle_timeout, &mut stopping).await? {
+                break;
             }
-            let mut val = String::new();
-            reader.read_line(&mut val).await?;
-            val = val.trim_matches(X).to_owned();
-            if val.len() == 0 {
-                writer.write_all("ErrNoVal\n".as_bytes()).await?;
-                return Ok(());
+            let frame = match protocol::read_frame_async(&mut reader, opts.max_request_len).await {
+                Ok(frame) => frame,
+                Err(e) if is_bad_request(&e) => {
+                    // the frame is not read, its id is unknown
+                    warn!(error = %e, "rejecting request");
+                    let reply = Reply::Error(Status::BadRequest, e.to_string());
+                    writer
+                        .write_all(&Response { id: 0, reply }.encode())
+                        .await?;
+                    None
+                }
+                Err(e) => return Err(e),
+            };
+            let open = match frame {
+                Some(frame) => v2_handler(&frame, &mut writer, eng.clone(), &opts).await?,
+                None => false,
+            };
+            // responses to pipelined requests are flushed together
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+            if !open {
+                break;
+            }
+        }
+    } else {
+        let mut op = first;
+        loop {
+            let served = legacy_handler(op, &mut reader, &mut writer, eng.clone(), &opts).await;
+            let open = match served {
+                Ok(open) => open,
+                // the rest of the request can not be told from the next one
+                Err(e) if is_bad_request(&e) => {
+                    warn!(error = %e, "rejecting request");
+                    writer.write_u8(protocol::GET_ERR).await?;
+                    write_field(&mut writer, e.to_string().as_bytes()).await?;
+                    false
+                }
+                Err(e) => return Err(e),
+            };
+            if !open {
+                break;
+            }
+            if reader.buffer().is_empty() {
+                writer.flush().await?;
+            }
+            op = match read_op(&mut reader, opts.idle_timeout, &mut stopping).await? {
+                Some(op) => op,
+                None => break,
+            };
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Whether `e` is caused by a request the server refuses, rather than by the
+/// connection or the engine
+fn is_bad_request(e: &failure::Error) -> bool {
+    matches!(
+        e.downcast_ref::<MyErr>(),
+        Some(MyErr::InvalidFrame | MyErr::UnknownOp(_) | MyErr::TooLarge(..))
+    )
+}
+
+/// Read the first byte of the next request, returns `None` if the connection
+/// is closed, idle for `idle_timeout` or the server is stopping
+async fn read_op<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<Timed<R>>,
+    idle_timeout: Duration,
+    stopping: &mut watch::Receiver<bool>,
+) -> Result<Option<u8>> {
+    if !next_request(reader, idle_timeout, stopping).await? {
+        return Ok(None);
+    }
+    Ok(Some(reader.read_u8().await?))
+}
+
+/// Wait for the next request to arrive, returns `false` if the connection is
+/// closed, idle for `idle_timeout` or the server is stopping. Requests
+/// already received are not served once the server is stopping
+async fn next_request<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<Timed<R>>,
+    idle_timeout: Duration,
+    stopping: &mut watch::Receiver<bool>,
+) -> Result<bool> {
+    if *stopping.borrow() {
+        return Ok(false);
+    }
+    // waiting between requests is bounded by `idle_timeout` instead
+    reader.get_mut().arm(false);
+    let arrived = tokio::select! {
+        filled = timeout(idle_timeout, reader.fill_buf()) => match filled {
+            Ok(buf) => !buf?.is_empty(),
+            Err(_) => {
+                debug!("closing idle connection");
+                false
             }
-            if let Err(_) = eng.set(key, val).await {
-                writer.write_all("ErrInternal\n".as_bytes()).await?;
+        },
+        _ = stopping.wait_for(|stop| *stop) => {
+            debug!("closing connection for shutdown");
+            false
+        }
+    };
+    reader.get_mut().arm(true);
+    Ok(arrived)
+}
+
+/// Refuse a connection over `max_connections` by answering its first request
+/// with an error
+async fn refuse(mut stream: TcpStream, protocol: Protocol) -> Result<()> {
+    let msg = "Too many connections";
+    let reply = match protocol {
+        Protocol::Resp => Value::Error("ERR max number of clients reached".to_owned()).encode(),
+        Protocol::Kvs => {
+            let mut reply = Vec::new();
+            if timeout(REFUSE_TIMEOUT, stream.read_u8()).await?? == protocol::HANDSHAKE[0] {
+                let mut hello = [0; 4];
+                timeout(REFUSE_TIMEOUT, stream.read_exact(&mut hello)).await??;
+                reply.extend_from_slice(&protocol::HANDSHAKE);
+                reply.push(protocol::VERSION_REFUSED);
             } else {
-                writer.write_all("OK\n".as_bytes()).await?;
+                reply.push(protocol::GET_ERR);
             }
+            write_field(&mut reply, msg.as_bytes()).await?;
+            reply
         }
-        protocol::OP_RM => {
-            let mut key = String::new();
-            reader.read_line(&mut key).await?;
-            key = key.trim_matches(X).to_owned();
-            if key.len() == 0 {
-                writer.write_all("ErrNoKey\n".as_bytes()).await?;
-                return Ok(());
+    };
+    stream.write_all(&reply).await?;
+    stream.shutdown().await?;
+    // closing with bytes unread would reset the connection, losing the reply
+    let mut sink = tokio::io::sink();
+    timeout(REFUSE_TIMEOUT, tokio::io::copy(&mut stream, &mut sink)).await??;
+    Ok(())
+}
+
+/// Serve a request framed by protocol v2, returns whether the connection
+/// stays open
+async fn v2_handler<E, W>(
+    frame: &[u8],
+    writer: &mut W,
+    eng: E,
+    opts: &ServerOptions,
+) -> Result<bool>
+where
+    E: KvsEngine,
+    W: AsyncWrite + Unpin,
+{
+    let decoded = Request::decode(frame).and_then(|req| {
+        req.cmd.check_len(opts.max_key_len, opts.max_val_len)?;
+        Ok(req)
+    });
+    let Request { id, cmd } = match decoded {
+        Ok(req) => req,
+        Err(e) => {
+            warn!(error = %e, "rejecting request");
+            // the id is still meaningful if only the body is malformed
+            let id = frame.get(..4).map_or(0, |b| {
+                u32::from_be_bytes(b.try_into().expect("slice of 4 bytes"))
+            });
+            let reply = Reply::Error(Status::BadRequest, e.to_string());
+            writer.write_all(&Response { id, reply }.encode()).await?;
+            return Ok(true);
+        }
+    };
+    let reply = match cmd {
+        Command::Set { key, val } => match eng.set(key, val).await {
+            Ok(()) => Reply::Done,
+            Err(e) => Reply::Error(Status::Internal, e.to_string()),
+        },
+        Command::SetWithTtl { key, val, ttl } => match eng.set_with_ttl(key, val, ttl).await {
+            Ok(()) => Reply::Done,
+            Err(e) => Reply::Error(Status::Internal, e.to_string()),
+        },
+        Command::Remove { key } => match eng.remove(key).await {
+            Ok(()) => Reply::Done,
+            Err(e) => match e.downcast_ref::<MyErr>() {
+                Some(MyErr::KeyNotFound) => Reply::NotFound,
+                _ => Reply::Error(Status::Internal, e.to_string()),
+            },
+        },
+        Command::CompareAndSwap { key, expected, new } => {
+            match eng.compare_and_swap(key, expected, new).await {
+                Ok(true) => Reply::Done,
+                Ok(false) => Reply::Conflict,
+                Err(e) => Reply::Error(Status::Internal, e.to_string()),
+            }
+        }
+        Command::Batch { batch } => match eng.apply_batch(batch).await {
+            Ok(()) => Reply::Done,
+            Err(e) => Reply::Error(Status::Internal, e.to_string()),
+        },
+        Command::Get { key } => match eng.get(key).await {
+            Ok(Some(val)) => Reply::Value(val),
+            Ok(None) => Reply::NotFound,
+            Err(e) => {
+                error!("get: err={}", e);
+                Reply::Error(Status::Internal, e.to_string())
             }
-            debug!("Removing {}", key);
-            if let Err(e) = eng.remove(key).await {
-                writer.write_all(e.to_string().as_bytes()).await?;
-                writer.write_all(&['\n' as u8]).await?;
+        },
+        Command::Scan { start, end, limit } => {
+            let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+            let pager = ScanPager::new(eng, start, end, None, limit);
+            write_v2_scan(writer, id, pager).await?;
+            return Ok(true);
+        }
+        Command::ScanPrefix { prefix, limit } => {
+            let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+            let pager = ScanPager::new(eng, prefix.clone(), None, Some(prefix), limit);
+            write_v2_scan(writer, id, pager).await?;
+            return Ok(true);
+        }
+        Command::Ping => Reply::Done,
+        Command::Close => {
+            let reply = Reply::Done;
+            writer.write_all(&Response { id, reply }.encode()).await?;
+            return Ok(false);
+        }
+    };
+    writer.write_all(&Response { id, reply }.encode()).await?;
+    Ok(true)
+}
+
+/// Stream a scan as `Partial` pages ended by `Ok`
+async fn write_v2_scan<E, W>(writer: &mut W, id: u32, mut pager: ScanPager<E>) -> Result<()>
+where
+    E: KvsEngine,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let reply = match pager.next_page().await {
+            Ok(Some(pairs)) if pairs.is_empty() => continue,
+            Ok(Some(pairs)) => Reply::Pairs(pairs),
+            Ok(None) => Reply::Done,
+            Err(e) => {
+                error!("scan: err={}", e);
+                Reply::Error(Status::Internal, e.to_string())
+            }
+        };
+        let last = !matches!(reply, Reply::Pairs(_));
+        writer.write_all(&Response { id, reply }.encode()).await?;
+        writer.flush().await?;
+        if last {
+            return Ok(());
+        }
+    }
+}
+
+/// Serve a request of the legacy protocol, whose op is already read. Returns
+/// whether the connection stays open
+async fn legacy_handler<E, R, W>(
+    op: u8,
+    reader: &mut R,
+    writer: &mut W,
+    eng: E,
+    opts: &ServerOptions,
+) -> Result<bool>
+where
+    E: KvsEngine,
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match op {
+        op @ (protocol::OP_SET | protocol::OP_SET_TTL) => {
+            let key = read_field(reader, "key", opts.max_key_len).await?;
+            // a legacy request is made of its fields only
+            let max_val_len = opts
+                .max_val_len
+                .min(opts.max_request_len.saturating_sub(key.len()));
+            let val = read_field(reader, "value", max_val_len).await?;
+            let written = if op == protocol::OP_SET_TTL {
+                let ttl = Duration::from_millis(reader.read_u64().await?);
+                eng.set_with_ttl(key, val, ttl).await
             } else {
-                writer.write_all("OK\n".as_bytes()).await?;
+                eng.set(key, val).await
+            };
+            if written.is_err() {
+                writer.write_u8(protocol::GET_ERR).await?;
+                write_field(writer, b"ErrInternal").await?;
+            } else {
+                writer.write_u8(protocol::RES_OK).await?;
+            }
+        }
+        protocol::OP_RM => {
+            let key = read_field(reader, "key", opts.max_key_len).await?;
+            debug!("Removing {}", String::from_utf8_lossy(&key));
+            match eng.remove(key).await {
+                Ok(()) => writer.write_u8(protocol::RES_OK).await?,
+                Err(e) => match e.downcast_ref::<MyErr>() {
+                    Some(MyErr::KeyNotFound) => writer.write_u8(protocol::GET_NIL).await?,
+                    _ => {
+                        writer.write_u8(protocol::GET_ERR).await?;
+                        write_field(writer, e.to_string().as_bytes()).await?;
+                    }
+                },
             }
         }
         protocol::OP_GET => {
-            let mut key = String::new();
-            reader.read_line(&mut key).await?;
-            key = key.trim_matches(X).to_owned();
-            if key.len() == 0 {
+            let key = read_field(reader, "key", opts.max_key_len).await?;
+            debug!("OP_GET key={}", String::from_utf8_lossy(&key));
+            match eng.get(key).await {
+                Ok(Some(v)) => {
+                    writer.write_u8(protocol::GET_VAL).await?;
+                    write_field(writer, &v).await?;
+                }
+                Ok(None) => writer.write_u8(protocol::GET_NIL).await?,
+                Err(e) => {
+                    error!("OP_GET: err={}", e);
+                    writer.write_u8(protocol::GET_ERR).await?;
+                    write_field(writer, b"ErrInternal").await?;
+                }
+            }
+        }
+        protocol::OP_CAS => {
+            let key = read_field(reader, "key", opts.max_key_len).await?;
+            let mut left = opts.max_request_len.saturating_sub(key.len());
+            let expected = read_opt_field(reader, "value", opts.max_val_len.min(left)).await?;
+            left -= expected.as_ref().map_or(0, Vec::len);
+            let new = read_opt_field(reader, "value", opts.max_val_len.min(left)).await?;
+            match eng.compare_and_swap(key, expected, new).await {
+                Ok(true) => writer.write_u8(protocol::RES_OK).await?,
+                Ok(false) => writer.write_u8(protocol::RES_CONFLICT).await?,
+                Err(e) => {
+                    writer.write_u8(protocol::GET_ERR).await?;
+                    write_field(writer, e.to_string().as_bytes()).await?;
+                }
+            }
+        }
+        protocol::OP_BATCH => {
+            let batch = read_batch(reader, opts).await?;
+            debug!("OP_BATCH writes={}", batch.len());
+            match eng.apply_batch(batch).await {
+                Ok(()) => writer.write_u8(protocol::RES_OK).await?,
+                Err(e) => {
+                    writer.write_u8(protocol::GET_ERR).await?;
+                    write_field(writer, e.to_string().as_bytes()).await?;
+                }
+            }
+        }
+        op @ (protocol::OP_SCAN | protocol::OP_SCAN_PREFIX) => {
+            let start = read_field(reader, "key", opts.max_key_len).await?;
+            let mut end = None;
+            if op == protocol::OP_SCAN {
+                end = Some(read_field(reader, "key", opts.max_key_len).await?)
+                    .filter(|e| !e.is_empty());
+            }
+            let limit = usize::try_from(reader.read_u64().await?).unwrap_or(usize::MAX);
+            // a prefix scan has no end, it stops at the first key without prefix
+            let prefix = Some(start.clone()).filter(|_| op == protocol::OP_SCAN_PREFIX);
+            debug!(
+                "OP_SCAN start={} end={:?} limit={}",
+                String::from_utf8_lossy(&start),
+                end.as_deref().map(String::from_utf8_lossy),
+                limit
+            );
+            let pager = ScanPager::new(eng, start, end, prefix, limit);
+            write_legacy_scan(writer, pager).await?;
+        }
+        protocol::OP_PING => writer.write_u8(protocol::RES_OK).await?,
+        protocol::OP_CLOSE => {
+            writer.write_u8(protocol::RES_OK).await?;
+            return Ok(false);
+        }
+        op => Err(MyErr::UnknownOp(op))?,
+    }
+    Ok(true)
+}
+
+/// Stream a scan as legacy items
+async fn write_legacy_scan<E, W>(writer: &mut W, mut pager: ScanPager<E>) -> Result<()>
+where
+    E: KvsEngine,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        match pager.next_page().await {
+            Ok(Some(pairs)) => {
+                for (k, v) in pairs {
+                    writer.write_u8(protocol::SCAN_ITEM).await?;
+                    write_field(writer, &k).await?;
+                    write_field(writer, &v).await?;
+                }
+                writer.flush().await?;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("OP_SCAN: err={}", e);
                 writer.write_u8(protocol::GET_ERR).await?;
-                writer.write_all("ErrNoKey\n".as_bytes()).await?;
+                write_field(writer, b"ErrInternal").await?;
                 return Ok(());
             }
-            debug!("OP_GET key={}", key);
-            let res = eng.get(key).await;
-            if let Err(e) = res {
-                error!("OP_GET: err={}", e);
-                writer.write_u8(protocol::GET_ERR).await?;
-                writer.write_all("ErrInternal\n".as_bytes()).await?;
-            } else {
-                if let Some(v) = res.unwrap() {
-                    writer.write_u8(protocol::GET_VAL).await?;
-                    writer.write_all(v.as_bytes()).await?;
-                } else {
-                    writer.write_u8(protocol::GET_NIL).await?;
+        }
+    }
+    writer.write_u8(protocol::SCAN_END).await?;
+    Ok(())
+}
+
+/// Read a length-prefixed field
+async fn read_field<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    what: &'static str,
+    max_len: usize,
+) -> Result<Vec<u8>> {
+    let len = reader.read_u32().await?;
+    if len as usize > max_len {
+        Err(MyErr::TooLarge(what, len.into(), max_len as u64))?
+    }
+    let mut buf = vec![0; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Read an optional field, `| 0: u8 |` or `| 1: u8 | field |`
+async fn read_opt_field<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    what: &'static str,
+    max_len: usize,
+) -> Result<Option<Vec<u8>>> {
+    match reader.read_u8().await? {
+        0 => Ok(None),
+        1 => Ok(Some(read_field(reader, what, max_len).await?)),
+        _ => Err(MyErr::InvalidFrame)?,
+    }
+}
+
+/// Read the writes of `OP_BATCH`. Their ops and length prefixes count
+/// towards the request limit too, so that the count of writes is bounded
+async fn read_batch<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    opts: &ServerOptions,
+) -> Result<WriteBatch> {
+    let count = reader.read_u32().await?;
+    let mut batch = WriteBatch::new();
+    let mut used = 4;
+    for _ in 0..count {
+        let op = reader.read_u8().await?;
+        if op != protocol::OP_SET && op != protocol::OP_RM {
+            Err(MyErr::UnknownOp(op))?
+        }
+        used += 5;
+        if used > opts.max_request_len {
+            Err(MyErr::TooLarge(
+                "request",
+                used as u64,
+                opts.max_request_len as u64,
+            ))?
+        }
+        let left = opts.max_request_len - used;
+        let key = read_field(reader, "key", opts.max_key_len.min(left)).await?;
+        used += key.len();
+        match op {
+            protocol::OP_SET => {
+                used += 4;
+                let left = opts.max_request_len.saturating_sub(used);
+                let val = read_field(reader, "value", opts.max_val_len.min(left)).await?;
+                used += val.len();
+                batch.set(key, val);
+            }
+            _ => batch.remove(key),
+        }
+    }
+    Ok(batch)
+}
+
+/// Write a length-prefixed field
+async fn write_field<W: AsyncWrite + Unpin>(writer: &mut W, buf: &[u8]) -> Result<()> {
+    writer.write_u32(buf.len() as u32).await?;
+    writer.write_all(buf).await?;
+    Ok(())
+}
+
+/// Serve RESP commands of a connection until it is closed
+pub async fn resp_handler<E: KvsEngine>(
+    mut stream: TcpStream,
+    eng: E,
+    opts: ServerOptions,
+    mut stopping: watch::Receiver<bool>,
+) -> Result<()> {
+    let (reader, writer) = stream.split();
+    let mut reader = BufReader::with_capacity(BUF_SIZE, Timed::new(reader, opts.read_timeout));
+    let mut writer = BufWriter::with_capacity(BUF_SIZE, Timed::new(writer, opts.write_timeout));
+    let mut cursors = ScanCursors::default();
+    loop {
+        if !next_request(&mut reader, opts.idle_timeout, &mut stopping).await? {
+            break;
+        }
+        let args = match resp::read_command(&mut reader, opts.max_request_len).await {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(e) if e.downcast_ref::<io::Error>().is_some() => return Err(e),
+            Err(e) => {
+                // the stream can not be resynchronized after a protocol error
+                warn!(error = %e, "rejecting request");
+                let reply = Value::Error(format!("ERR {}", e));
+                writer.write_all(&reply.encode()).await?;
+                break;
+            }
+        };
+        let name = match args.first() {
+            Some(name) => String::from_utf8_lossy(name).to_ascii_uppercase(),
+            None => continue,
+        };
+        let quit = name == "QUIT";
+        let reply = if quit {
+            Value::Simple("OK".to_owned())
+        } else {
+            match exec_resp(&name, args, eng.clone(), &mut cursors, &opts).await {
+                Ok(reply) => reply,
+                Err(e) => {
+                    error!("{}: err={}", name, e);
+                    Value::Error(format!("ERR {}", e))
                 }
-                writer.write_u8('\n' as u8).await?;
             }
+        };
+        writer.write_all(&reply.encode()).await?;
+        if quit {
+            break;
         }
-        _ => {
-            panic!("unknown operation");
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
         }
     }
     writer.flush().await?;
     Ok(())
 }
+
+/// Execute a RESP command named `name`, the first of `args`
+async fn exec_resp<E: KvsEngine>(
+    name: &str,
+    mut args: Vec<Vec<u8>>,
+    eng: E,
+    cursors: &mut ScanCursors,
+    opts: &ServerOptions,
+) -> Result<Value> {
+    let arity_err = || {
+        Value::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))
+    };
+    let syntax_err = || Value::Error("ERR syntax error".to_owned());
+    let expire_err = || {
+        Value::Error(format!(
+            "ERR invalid expire time in '{}' command",
+            name.to_ascii_lowercase()
+        ))
+    };
+    args.remove(0);
+    Ok(match name {
+        "PING" => match args.pop() {
+            None => Value::Simple("PONG".to_owned()),
+            Some(msg) if args.is_empty() => Value::Bulk(msg),
+            Some(_) => arity_err(),
+        },
+        "SET" if args.len() < 2 => arity_err(),
+        // only an expiry option `EX seconds` or `PX milliseconds` is supported
+        "SET" if args.len() != 2 && args.len() != 4 => syntax_err(),
+        "SET" => {
+            let ttl = match args.get(2..) {
+                Some([unit, n]) => {
+                    let from: fn(u64) -> Duration = match unit.to_ascii_uppercase().as_slice() {
+                        b"EX" => Duration::from_secs,
+                        b"PX" => Duration::from_millis,
+                        _ => return Ok(syntax_err()),
+                    };
+                    match parse_resp_int(n) {
+                        Some(n) if n > 0 => Some(from(n as u64)),
+                        _ => return Ok(expire_err()),
+                    }
+                }
+                _ => None,
+            };
+            args.truncate(2);
+            let val = args.pop().expect("checked arity");
+            let key = args.pop().expect("checked arity");
+            protocol::check_len("key", &key, opts.max_key_len)?;
+            protocol::check_len("value", &val, opts.max_val_len)?;
+            match ttl {
+                Some(ttl) => eng.set_with_ttl(key, val, ttl).await?,
+                None => eng.set(key, val).await?,
+            }
+            Value::Simple("OK".to_owned())
+        }
+        "MSET" if args.is_empty() || !args.len().is_multiple_of(2) => arity_err(),
+        "MSET" => {
+            let mut batch = WriteBatch::new();
+            let mut args = args.into_iter();
+            while let (Some(key), Some(val)) = (args.next(), args.next()) {
+                protocol::check_len("key", &key, opts.max_key_len)?;
+                protocol::check_len("value", &val, opts.max_val_len)?;
+                batch.set(key, val);
+            }
+            eng.apply_batch(batch).await?;
+            Value::Simple("OK".to_owned())
+        }
+        "GET" if args.len() != 1 => arity_err(),
+        "GET" => match eng.get(args.pop().expect("checked arity")).await? {
+            Some(val) => Value::Bulk(val),
+            None => Value::Nil,
+        },
+        "DEL" | "EXISTS" if args.is_empty() => arity_err(),
+        "DEL" => {
+            let mut n = 0;
+            for key in args {
+                match eng.clone().remove(key).await {
+                    Ok(()) => n += 1,
+                    Err(e) => match e.downcast_ref::<MyErr>() {
+                        Some(MyErr::KeyNotFound) => {}
+                        _ => return Err(e),
+                    },
+                }
+            }
+            Value::Integer(n)
+        }
+        "EXISTS" => {
+            let mut n = 0;
+            for key in args {
+                if eng.clone().get(key).await?.is_some() {
+                    n += 1;
+                }
+            }
+            Value::Integer(n)
+        }
+        "SCAN" if args.is_empty() => arity_err(),
+        // options come in pairs after the cursor
+        "SCAN" if args.len().is_multiple_of(2) => syntax_err(),
+        "SCAN" => {
+            let mut opts = args.split_off(1).into_iter();
+            let mut pattern = None;
+            let mut count = RESP_SCAN_COUNT;
+            while let Some(opt) = opts.next() {
+                let arg = opts.next().expect("options come in pairs");
+                match opt.to_ascii_uppercase().as_slice() {
+                    b"MATCH" => pattern = Some(arg),
+                    // a hint only, capped so one call can not read all keys
+                    b"COUNT" => match parse_resp_int(&arg) {
+                        Some(n) if n > 0 => count = n.min(SCAN_PAGE),
+                        _ => return Ok(syntax_err()),
+                    },
+                    _ => return Ok(syntax_err()),
+                }
+            }
+            let start = match parse_resp_int(&args[0]) {
+                Some(0) => Vec::new(),
+                Some(cursor) => match cursors.get(cursor as u64) {
+                    Some(start) => start,
+                    None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+                },
+                None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+            };
+            // only keys starting with the literal prefix of pattern can match
+            let prefix = pattern.as_deref().map_or(&[][..], glob_prefix).to_vec();
+            let start = start.max(prefix.clone());
+            let pairs = eng.scan(start, None, count).await?;
+            let exhausted = pairs.len() < count;
+            let mut next = None;
+            let mut keys = Vec::new();
+            for (key, _) in pairs {
+                if !key.starts_with(&prefix) {
+                    next = None;
+                    break;
+                }
+                if pattern.as_ref().is_none_or(|p| glob_match(p, &key)) {
+                    keys.push(Value::Bulk(key.clone()));
+                }
+                next = Some(key);
+            }
+            let cursor = match next {
+                Some(mut last) if !exhausted => {
+                    // the smallest key greater than `last`
+                    last.push(0);
+                    cursors.insert(last)
+                }
+                _ => 0,
+            };
+            Value::Array(vec![
+                Value::Bulk(cursor.to_string().into_bytes()),
+                Value::Array(keys),
+            ])
+        }
+        "INFO" => {
+            let info = format!(
+                "# Server\r\nkvs_version:{}\r\nredis_mode:standalone\r\nprocess_id:{}\r\n",
+                env!("CARGO_PKG_VERSION"),
+                std::process::id()
+            );
+            Value::Bulk(info.into_bytes())
+        }
+        _ => Value::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_ascii_lowercase()
+        )),
+    })
+}
+
+fn parse_resp_int(arg: &[u8]) -> Option<usize> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
+
+/// Cursors of RESP `SCAN` issued by a connection, each maps to the key where
+/// the next call starts. Only the latest ones are kept
+#[derive(Default)]
+struct ScanCursors {
+    last: u64,
+    starts: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl ScanCursors {
+    fn insert(&mut self, start: Vec<u8>) -> u64 {
+        self.last += 1;
+        self.starts.push_back((self.last, start));
+        if self.starts.len() > RESP_MAX_CURSORS {
+            self.starts.pop_front();
+        }
+        self.last
+    }
+    fn get(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.starts
+            .iter()
+            .find(|(c, _)| *c == cursor)
+            .map(|(_, start)| start.clone())
+    }
+}
+
+/// Literal bytes a glob pattern starts with
+fn glob_prefix(pattern: &[u8]) -> &[u8] {
+    let n = pattern
+        .iter()
+        .position(|c| b"*?[\\".contains(c))
+        .unwrap_or(pattern.len());
+    &pattern[..n]
+}
+
+/// Match `s` against a glob pattern of Redis, which supports `*`, `?`,
+/// `[...]` with `^` and ranges, and `\` escapes
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    // position of the last `*` and where it began to match
+    let mut star = None;
+    while si < s.len() {
+        if pattern.get(pi) == Some(&b'*') {
+            star = Some((pi, si));
+            pi += 1;
+        } else if let Some(n) = pattern
+            .get(pi..)
+            .filter(|p| !p.is_empty())
+            .and_then(|p| glob_match_one(p, s[si]))
+        {
+            pi += n;
+            si += 1;
+        } else if let Some((sp, ss)) = star {
+            // let the `*` match one more byte
+            pi = sp + 1;
+            si = ss + 1;
+            star = Some((sp, ss + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[pi..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the first token of a non-empty pattern, returns the
+/// length of the token if matched
+fn glob_match_one(pattern: &[u8], c: u8) -> Option<usize> {
+    match pattern[0] {
+        b'?' => Some(1),
+        b'\\' if pattern.len() > 1 => (pattern[1] == c).then_some(2),
+        b'[' => {
+            let negate = pattern.get(1) == Some(&b'^');
+            let mut i = if negate { 2 } else { 1 };
+            let mut matched = false;
+            loop {
+                match pattern.get(i) {
+                    // unclosed, `[` is literal
+                    None => return (c == b'[').then_some(1),
+                    Some(b']') => break,
+                    Some(b'\\') if i + 1 < pattern.len() => {
+                        matched |= pattern[i + 1] == c;
+                        i += 2;
+                    }
+                    Some(&lo)
+                        if pattern.get(i + 1) == Some(&b'-')
+                            && pattern.get(i + 2).is_some_and(|&hi| hi != b']') =>
+                    {
+                        let hi = pattern[i + 2];
+                        matched |= (lo.min(hi)..=lo.max(hi)).contains(&c);
+                        i += 3;
+                    }
+                    Some(&x) => {
+                        matched |= x == c;
+                        i += 1;
+                    }
+                }
+            }
+            (matched != negate).then_some(i + 1)
+        }
+        x => (x == c).then_some(1),
+    }
+}
+
+/// Read a scan from engine page by page, so that a large scan is never held
+/// in memory as a whole
+struct ScanPager<E> {
+    eng: E,
+    /// Start of the next page, `None` once exhausted
+    cursor: Option<Vec<u8>>,
+    end: Option<Vec<u8>>,
+    /// A prefix scan has no end, it stops at the first key without prefix
+    prefix: Option<Vec<u8>>,
+    limit: usize,
+    first: bool,
+}
+
+impl<E: KvsEngine> ScanPager<E> {
+    fn new(
+        eng: E,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        prefix: Option<Vec<u8>>,
+        limit: usize,
+    ) -> Self {
+        ScanPager {
+            eng,
+            cursor: Some(start),
+            end,
+            prefix,
+            limit,
+            first: true,
+        }
+    }
+    async fn next_page(&mut self) -> Result<Option<Vec<(Vec<u8>, Vec<u8>)>>> {
+        let start = match self.cursor.take() {
+            Some(start) if self.limit > 0 => start,
+            _ => return Ok(None),
+        };
+        let n = self.limit.min(SCAN_PAGE);
+        let pairs: Vec<_> = match &self.prefix {
+            Some(prefix) if self.first => self.eng.clone().scan_prefix(prefix.clone(), n).await?,
+            _ => {
+                let pairs = self.eng.clone().scan(start, self.end.clone(), n).await?;
+                let in_prefix = |k: &Vec<u8>| self.prefix.as_ref().is_none_or(|p| k.starts_with(p));
+                pairs
+                    .into_iter()
+                    .take_while(|(k, _)| in_prefix(k))
+                    .collect()
+            }
+        };
+        self.first = false;
+        self.limit -= pairs.len();
+        if pairs.len() == n {
+            if let Some((last, _)) = pairs.last() {
+                // the smallest key greater than `last`
+                let mut next = last.clone();
+                next.push(0);
+                self.cursor = Some(next);
+            }
+        }
+        Ok(Some(pairs))
+    }
+}
+
+/// Stream whose operations fail with `TimedOut` once pending for `timeout`
+/// without progress. Operations are not timed while disarmed
+struct Timed<S> {
+    io: S,
+    timeout: Duration,
+    armed: bool,
+    deadline: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S: Unpin> Timed<S> {
+    fn new(io: S, timeout: Duration) -> Self {
+        Timed {
+            io,
+            timeout,
+            armed: true,
+            deadline: None,
+        }
+    }
+    fn arm(&mut self, armed: bool) {
+        self.armed = armed;
+        self.deadline = None;
+    }
+    fn poll_timed<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        op: impl FnOnce(Pin<&mut S>, &mut Context<'_>) -> Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if let Poll::Ready(rlt) = op(Pin::new(&mut self.io), cx) {
+            self.deadline = None;
+            return Poll::Ready(rlt);
+        }
+        if !self.armed {
+            return Poll::Pending;
+        }
+        let timeout = self.timeout;
+        let deadline = self
+            .deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        match deadline.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.deadline = None;
+                Poll::Ready(Err(io::ErrorKind::TimedOut.into()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Timed<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_timed(cx, |io, cx| io.poll_read(cx, buf))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Timed<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_timed(cx, |io, cx| io.poll_write(cx, buf))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_timed(cx, |io, cx| io.poll_flush(cx))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_timed(cx, |io, cx| io.poll_shutdown(cx))
+    }
+}
@@ -113,6 +113,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.install(|| job());
+        self.pool.install(job);
     }
 }
@@ -0,0 +1,130 @@
+mod common;
+
+use common::serve;
+use kvs::client::{AsyncClient, ClientError, ClientOptions};
+use kvs::protocol::Reply;
+use kvs::server::ServerOptions;
+use kvs::Result;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+// Operations should behave like those of the blocking client
+#[tokio::test]
+async fn async_client_operations() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4020";
+    serve(&temp_dir, addr, ServerOptions::new());
+
+    let mut client = AsyncClient::connect(addr).await?;
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(
+        client.get(b"key1".to_vec()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(client.get(b"key2".to_vec()).await?, None);
+    assert!(client.remove(b"key1".to_vec()).await?);
+    assert!(!client.remove(b"key1".to_vec()).await?);
+
+    for i in 0..300 {
+        let key = format!("key{:03}", i).into_bytes();
+        client.set(key, b"v".to_vec()).await?;
+    }
+    let pairs = client
+        .scan(b"key100".to_vec(), Some(b"key200".to_vec()), usize::MAX)
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0].0, b"key100");
+    let pairs = client
+        .scan_prefix(b"key2".to_vec(), 10)
+        .await?
+        .collect()
+        .await?;
+    assert_eq!(pairs.len(), 10);
+
+    let replies = client
+        .pipeline()
+        .set(b"a".to_vec(), b"1".to_vec())
+        .get(b"a".to_vec())
+        .remove(b"b".to_vec())
+        .execute()
+        .await?;
+    assert_eq!(
+        replies,
+        vec![Reply::Done, Reply::Value(b"1".to_vec()), Reply::NotFound]
+    );
+    client.close().await?;
+    Ok(())
+}
+
+// Cancelled requests and abandoned scans should leave the connection usable
+#[tokio::test]
+async fn async_client_cancellation() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4021";
+    serve(&temp_dir, addr, ServerOptions::new());
+
+    let mut client = AsyncClient::connect(addr).await?;
+    let val = vec![b'v'; 64 * 1024];
+    for i in 0..200 {
+        client
+            .set(format!("key{:03}", i).into_bytes(), val.clone())
+            .await?;
+    }
+
+    // cancel requests at any point
+    for _ in 0..20 {
+        let _ = timeout(Duration::ZERO, client.get(b"key000".to_vec())).await;
+        let _ = timeout(Duration::ZERO, client.set(b"key001".to_vec(), val.clone())).await;
+    }
+    // drop a scan before its end
+    let mut scan = client.scan_prefix(b"key".to_vec(), usize::MAX).await?;
+    assert!(scan.next().await.unwrap().is_ok());
+    drop(scan);
+
+    assert_eq!(client.get(b"key199".to_vec()).await?, Some(val.clone()));
+    assert_eq!(client.get(b"key000".to_vec()).await?, Some(val.clone()));
+    assert_eq!(client.get(b"nope".to_vec()).await?, None);
+    Ok(())
+}
+
+// A server never answering should fail connecting and requests by timeout
+#[tokio::test]
+async fn async_client_timeouts() -> Result<()> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:4022").await?;
+    tokio::spawn(async move {
+        let mut conns = Vec::new();
+        while let Ok((conn, _)) = listener.accept().await {
+            conns.push(conn);
+        }
+    });
+    let opts = ClientOptions::new().connect_timeout(Duration::from_millis(100));
+    let err = AsyncClient::connect_with("127.0.0.1:4022", opts)
+        .await
+        .err()
+        .expect("connecting should time out");
+    assert!(matches!(err, ClientError::Timeout));
+
+    // handshake is acked, requests are never answered
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:4023").await?;
+    tokio::spawn(async move {
+        let mut conns = Vec::new();
+        while let Ok((mut conn, _)) = listener.accept().await {
+            let mut hello = [0; 5];
+            conn.read_exact(&mut hello).await.unwrap();
+            conn.write_all(&hello).await.unwrap();
+            conns.push(conn);
+        }
+    });
+    let opts = ClientOptions::new().request_timeout(Duration::from_millis(100));
+    let mut client = AsyncClient::connect_with("127.0.0.1:4023", opts).await?;
+    let err = client
+        .get(b"key".to_vec())
+        .await
+        .expect_err("request should time out");
+    assert!(matches!(err, ClientError::Timeout));
+    Ok(())
+}
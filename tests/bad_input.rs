@@ -0,0 +1,197 @@
+mod common;
+
+use common::serve;
+use kvs::client::Client;
+use kvs::protocol::{self, Command, Reply, Request, Response, Status};
+use kvs::resp::{self, Value};
+use kvs::server::{Protocol, ServerOptions};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use tempfile::TempDir;
+
+/// Serve a new store speaking `protocol` on `addr` in background
+fn start_server(dir: &TempDir, addr: &str, protocol: Protocol) {
+    serve(dir, addr, ServerOptions::new().protocol(protocol));
+}
+
+/// Check the server still serves new connections
+fn assert_alive(addr: &str) {
+    let mut client = Client::connect(addr).unwrap();
+    client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some(b"value".to_vec())
+    );
+}
+
+/// Read a legacy error reply, then expect the connection closed
+fn read_legacy_error(stream: &mut TcpStream) -> String {
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest[0], protocol::GET_ERR);
+    let len = u32::from_be_bytes(rest[1..5].try_into().unwrap()) as usize;
+    assert_eq!(rest.len(), 5 + len, "connection should be closed");
+    String::from_utf8(rest[5..].to_vec()).unwrap()
+}
+
+/// Connect and start a v2 session
+fn v2_connect(addr: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&protocol::HANDSHAKE).unwrap();
+    stream.write_all(&[protocol::VERSION]).unwrap();
+    let mut ack = [0; 5];
+    stream.read_exact(&mut ack).unwrap();
+    stream
+}
+
+fn v2_recv(stream: &mut TcpStream) -> Response {
+    let frame = protocol::read_frame(stream).unwrap().unwrap();
+    Response::decode(&frame).unwrap()
+}
+
+// An unknown legacy op should be answered with an error, not crash the server
+#[test]
+fn legacy_unknown_op() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4031";
+    start_server(&temp_dir, addr, Protocol::Kvs);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"!").unwrap();
+    assert!(read_legacy_error(&mut stream).contains("Unknown operation"));
+    assert_alive(addr);
+}
+
+// Legacy keys and values over the limits should be refused before read
+#[test]
+fn legacy_too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4032";
+    start_server(&temp_dir, addr, Protocol::Kvs);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&[protocol::OP_GET]).unwrap();
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    assert!(read_legacy_error(&mut stream).contains("key of 4294967295 bytes"));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = vec![protocol::OP_SET];
+    req.extend_from_slice(&3_u32.to_be_bytes());
+    req.extend_from_slice(b"key");
+    req.extend_from_slice(&(protocol::MAX_VAL_LEN as u32 + 1).to_be_bytes());
+    stream.write_all(&req).unwrap();
+    assert!(read_legacy_error(&mut stream).contains("value of"));
+    assert_alive(addr);
+}
+
+// A v2 frame over the limit should be refused before read
+#[test]
+fn v2_frame_too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4033";
+    start_server(&temp_dir, addr, Protocol::Kvs);
+
+    let mut stream = v2_connect(addr);
+    stream.write_all(&u32::MAX.to_be_bytes()).unwrap();
+    let resp = v2_recv(&mut stream);
+    assert_eq!(resp.id, 0);
+    assert!(matches!(resp.reply, Reply::Error(Status::BadRequest, msg) if msg.contains("frame")));
+    assert_eq!(protocol::read_frame(&mut stream).unwrap(), None);
+    assert_alive(addr);
+}
+
+// A v2 key over the limit or a malformed frame should be answered with
+// `BadRequest`, keeping the connection usable
+#[test]
+fn v2_bad_fields() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4034";
+    start_server(&temp_dir, addr, Protocol::Kvs);
+
+    let mut stream = v2_connect(addr);
+    let key = vec![b'k'; protocol::MAX_KEY_LEN + 1];
+    let req = Request {
+        id: 7,
+        cmd: Command::Get { key },
+    };
+    stream.write_all(&req.encode()).unwrap();
+    let resp = v2_recv(&mut stream);
+    assert_eq!(resp.id, 7);
+    assert!(matches!(resp.reply, Reply::Error(Status::BadRequest, msg) if msg.contains("key of")));
+
+    // a field longer than its frame
+    let mut frame = Request {
+        id: 8,
+        cmd: Command::Get { key: b"k".to_vec() },
+    }
+    .encode();
+    frame[9..13].copy_from_slice(&100_u32.to_be_bytes());
+    stream.write_all(&frame).unwrap();
+    let resp = v2_recv(&mut stream);
+    assert_eq!(resp.id, 8);
+    assert_eq!(resp.reply.status(), Status::BadRequest);
+
+    let req = Request {
+        id: 9,
+        cmd: Command::Ping,
+    };
+    stream.write_all(&req.encode()).unwrap();
+    assert_eq!(
+        v2_recv(&mut stream),
+        Response {
+            id: 9,
+            reply: Reply::Done
+        }
+    );
+}
+
+/// Read a refused handshake, then expect the connection closed
+fn read_refused_handshake(stream: &mut TcpStream) -> String {
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest[..4], protocol::HANDSHAKE);
+    assert_eq!(rest[4], protocol::VERSION_REFUSED);
+    let len = u32::from_be_bytes(rest[5..9].try_into().unwrap()) as usize;
+    assert_eq!(rest.len(), 9 + len);
+    String::from_utf8(rest[9..].to_vec()).unwrap()
+}
+
+// An unsupported handshake should be refused, then the connection closed
+#[test]
+fn bad_handshake() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4035";
+    start_server(&temp_dir, addr, Protocol::Kvs);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"\0KVS\x09").unwrap();
+    assert_eq!(read_refused_handshake(&mut stream), "unsupported version 9");
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"\0KVX\x02").unwrap();
+    assert_eq!(read_refused_handshake(&mut stream), "invalid handshake");
+    assert_alive(addr);
+}
+
+// A RESP key over the limit should be answered with an error
+#[test]
+fn resp_too_large() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4036";
+    start_server(&temp_dir, addr, Protocol::Resp);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let key = vec![b'k'; protocol::MAX_KEY_LEN + 1];
+    let mut req = format!("*3\r\n$3\r\nSET\r\n${}\r\n", key.len()).into_bytes();
+    req.extend_from_slice(&key);
+    req.extend_from_slice(b"\r\n$1\r\nv\r\n*1\r\n$4\r\nPING\r\n");
+    stream.write_all(&req).unwrap();
+    let mut reader = BufReader::new(stream);
+    match resp::read_value(&mut reader).unwrap() {
+        Value::Error(msg) => assert!(msg.contains("key of"), "{}", msg),
+        v => panic!("unexpected reply {:?}", v),
+    }
+    assert_eq!(
+        resp::read_value(&mut reader).unwrap(),
+        Value::Simple("PONG".to_owned())
+    );
+}
@@ -0,0 +1,76 @@
+//! Servers started by integration tests. Each test file uses part of it.
+#![allow(dead_code)]
+
+use assert_cmd::prelude::*;
+use kvs::server::{self, ServerOptions};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvStoreOptions};
+use std::future;
+use std::io::Read;
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Longest wait for a server to accept connections
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Start `kvs-server` with the kvs engine in `dir`, listening on `addr`, with
+/// `extra` arguments. Returns once it accepts connections
+pub fn start_server(dir: impl AsRef<Path>, addr: &str, extra: &[&str]) -> Child {
+    let child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .args(extra)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    wait_for_server(addr);
+    child
+}
+
+pub fn stop_server(mut child: Child) {
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait server");
+}
+
+/// Serve a new `KvStore` in `dir` on `addr` in background, returns once it
+/// accepts connections
+pub fn serve(dir: impl AsRef<Path>, addr: &str, opts: ServerOptions) {
+    let (path, owned_addr) = (dir.as_ref().to_owned(), addr.to_owned());
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let pool = SharedQueueThreadPool::new(4).unwrap();
+        let store = KvStore::open(path, pool, KvStoreOptions::new()).unwrap();
+        rt.block_on(server::run(&owned_addr, store, opts, future::pending()))
+            .unwrap();
+    });
+    wait_for_server(addr);
+}
+
+/// Poll `addr` until a connection to it is accepted, then wait for the server
+/// to close it, so that it no longer counts against a connection limit
+pub fn wait_for_server(addr: &str) {
+    let deadline = Instant::now() + START_TIMEOUT;
+    loop {
+        match TcpStream::connect(addr) {
+            Ok(mut stream) => {
+                stream.set_read_timeout(Some(START_TIMEOUT)).unwrap();
+                stream.shutdown(Shutdown::Write).unwrap();
+                let _ = stream.read_to_end(&mut Vec::new());
+                return;
+            }
+            Err(e) => {
+                assert!(
+                    Instant::now() < deadline,
+                    "server on {} not up: {}",
+                    addr,
+                    e
+                );
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+}
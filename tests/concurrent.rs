@@ -1,5 +1,6 @@
 use assert_cmd::cargo::CommandCargoExt;
 use kvs::client::Client;
+use kvs::WriteBatch;
 use std::net::TcpStream;
 use std::process::Command;
 use std::sync::mpsc;
@@ -15,13 +16,14 @@ fn concurrent_access_server(engine: &str, num: usize) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", ADDR])
+        .args(["--engine", engine, "--addr", ADDR])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -31,17 +33,20 @@ fn concurrent_access_server(engine: &str, num: usize) {
         let ba = barrier.clone();
         let h = thread::spawn(move || {
             ba.wait();
-            client_set(id, "1");
-            client_set(id, "2");
-            client_get(id, "2\n");
+            let stream = TcpStream::connect(ADDR).expect("client can not connect");
+            let mut cli = Client::new(stream);
+            client_set(&mut cli, id, "1");
+            client_set(&mut cli, id, "2");
+            client_get(&mut cli, id, Some("2"));
 
-            client_rm(id);
-            client_get(id, "Key not found");
+            client_rm(&mut cli, id);
+            client_get(&mut cli, id, None);
 
-            client_set(id, "3");
+            client_set(&mut cli, id, "3");
             for _ in 0..10 {
-                client_get(id, "3\n");
+                client_get(&mut cli, id, Some("3"));
             }
+            cli.close().expect("client can not close");
         });
         handles.push(h);
     }
@@ -55,38 +60,162 @@ fn concurrent_access_server(engine: &str, num: usize) {
     handle.join().expect("can not stop server")
 }
 
-fn client_set(id: usize, val: &str) {
-    let stream = TcpStream::connect(ADDR).expect("client can not connect");
-    let mut cli = Client::new(stream);
+fn client_set(cli: &mut Client, id: usize, val: &str) {
     let key = gen_key(id);
-    cli.set(key, val.to_owned()).expect("client can not set");
+    cli.set(key, val.into()).expect("client can not set");
 }
 
-fn client_rm(id: usize) {
-    let stream = TcpStream::connect(ADDR).expect("client can not connect");
-    let mut cli = Client::new(stream);
+fn client_rm(cli: &mut Client, id: usize) {
     let key = gen_key(id);
     cli.remove(key).expect("client can not rm");
 }
 
-fn client_get(id: usize, expect: &str) {
-    let stream = TcpStream::connect(ADDR).expect("client can not connect");
-    let mut cli = Client::new(stream);
+fn client_get(cli: &mut Client, id: usize, expect: Option<&str>) {
     let key = gen_key(id);
     let got = cli.get(key).expect("can not get");
-    let expect = expect.to_owned();
-    assert_eq!(got, expect);
+    assert_eq!(got.as_deref(), expect.map(str::as_bytes));
 }
 
-fn gen_key(id: usize) -> String {
+fn gen_key(id: usize) -> Vec<u8> {
     format!(
         "key-prefix-is-long-long-long-long-long-long-long-long-long-long-long {}",
         id
     )
-    .to_owned()
+    .into_bytes()
 }
 
 #[test]
 fn concurrent_access_server_kvs_engine() {
     concurrent_access_server("kvs", 1000);
 }
+
+// Counters incremented by racing compare and swap loops should lose no update,
+// and exactly one racing `set_if_absent` should win
+fn concurrent_compare_and_swap(engine: &str, addr: &'static str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let (threads, increments) = (8, 25);
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|id| {
+            let ba = barrier.clone();
+            thread::spawn(move || {
+                let mut cli = Client::connect(addr).unwrap();
+                ba.wait();
+                let won = cli
+                    .set_if_absent(b"owner".to_vec(), id.to_string().into_bytes())
+                    .unwrap();
+                for _ in 0..increments {
+                    loop {
+                        let current = cli.get(b"counter".to_vec()).unwrap();
+                        let n: usize = current
+                            .as_deref()
+                            .map_or(0, |v| std::str::from_utf8(v).unwrap().parse().unwrap());
+                        let new = Some((n + 1).to_string().into_bytes());
+                        if cli
+                            .compare_and_swap(b"counter".to_vec(), current, new)
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+                won
+            })
+        })
+        .collect();
+    let winners = handles
+        .into_iter()
+        .map(|h| h.join().unwrap())
+        .filter(|&won| won)
+        .count();
+    assert_eq!(winners, 1);
+
+    let mut cli = Client::connect(addr).unwrap();
+    let total = (threads * increments).to_string().into_bytes();
+    assert_eq!(cli.get(b"counter".to_vec()).unwrap(), Some(total.clone()));
+    assert!(!cli
+        .compare_and_swap(b"counter".to_vec(), Some(b"0".to_vec()), None)
+        .unwrap());
+    assert!(cli
+        .compare_and_swap(b"counter".to_vec(), Some(total), None)
+        .unwrap());
+    assert_eq!(cli.get(b"counter".to_vec()).unwrap(), None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait server");
+}
+
+#[test]
+fn concurrent_compare_and_swap_kvs_engine() {
+    concurrent_compare_and_swap("kvs", "127.0.0.1:4045");
+}
+
+#[test]
+fn concurrent_compare_and_swap_sled_engine() {
+    concurrent_compare_and_swap("sled", "127.0.0.1:4046");
+}
+
+// Batches racing over the same keys should never be interleaved, the keys
+// end up all written by the same batch
+fn concurrent_apply_batch(engine: &str, addr: &'static str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let threads = 8;
+    let keys: Vec<Vec<u8>> = (0..10).map(|i| format!("key{}", i).into_bytes()).collect();
+    let barrier = Arc::new(Barrier::new(threads));
+    let handles: Vec<_> = (0..threads)
+        .map(|id| {
+            let (ba, keys) = (barrier.clone(), keys.clone());
+            thread::spawn(move || {
+                let mut cli = Client::connect(addr).unwrap();
+                ba.wait();
+                for round in 0..20 {
+                    let mut batch = WriteBatch::new();
+                    for key in &keys {
+                        batch.set(key.clone(), format!("{}-{}", id, round).into_bytes());
+                    }
+                    batch.remove(b"gone".to_vec());
+                    cli.apply_batch(batch).unwrap();
+                }
+            })
+        })
+        .collect();
+    for h in handles {
+        h.join().unwrap();
+    }
+
+    let mut cli = Client::connect(addr).unwrap();
+    let first = cli.get(keys[0].clone()).unwrap().unwrap();
+    assert!(first.ends_with(b"-19"));
+    for key in &keys[1..] {
+        assert_eq!(cli.get(key.clone()).unwrap(), Some(first.clone()));
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait server");
+}
+
+#[test]
+fn concurrent_apply_batch_kvs_engine() {
+    concurrent_apply_batch("kvs", "127.0.0.1:4047");
+}
+
+#[test]
+fn concurrent_apply_batch_sled_engine() {
+    concurrent_apply_batch("sled", "127.0.0.1:4048");
+}
@@ -0,0 +1,172 @@
+mod common;
+
+use common::stop_server;
+use kvs::client::Client;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::Child;
+use tempfile::TempDir;
+
+const ADDR: &str = "127.0.0.1:4018";
+const HTTP_ADDR: &str = "127.0.0.1:4019";
+
+fn start_server(dir: &TempDir) -> Child {
+    start_server_with(dir, ADDR, HTTP_ADDR, &[])
+}
+
+fn start_server_with(dir: &TempDir, addr: &str, http_addr: &str, extra: &[&str]) -> Child {
+    let mut args = vec!["--http-addr", http_addr];
+    args.extend_from_slice(extra);
+    let child = common::start_server(dir, addr, &args);
+    common::wait_for_server(http_addr);
+    child
+}
+
+/// Send a request, returns status code and body of the response
+fn request(method: &str, target: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let head = format!("Content-Length: {}\r\n", body.len());
+    request_to(HTTP_ADDR, method, target, &head, body)
+}
+
+/// Send a request with extra `headers` lines to `addr`
+fn request_to(
+    addr: &str,
+    method: &str,
+    target: &str,
+    headers: &str,
+    body: &[u8],
+) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let head = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Connection: close\r\n\r\n",
+        method, target, headers
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    // the server may reply before the whole body is sent
+    let _ = stream.write_all(body);
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    let split = resp.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&resp[..split]).into_owned();
+    let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+    (status, resp[split + 4..].to_vec())
+}
+
+fn json_body(body: &[u8]) -> Value {
+    serde_json::from_slice(body).unwrap()
+}
+
+// Keys should be served over HTTP with status codes mapped from errors
+#[test]
+fn http_gateway() {
+    let temp_dir = TempDir::new().unwrap();
+    let server = start_server(&temp_dir);
+
+    let (status, body) = request("GET", "/health", b"");
+    assert_eq!(status, 200);
+    assert_eq!(json_body(&body), json!({"status": "ok"}));
+
+    assert_eq!(request("PUT", "/keys/key1", b"value1").0, 204);
+    assert_eq!(request("GET", "/keys/key1", b""), (200, b"value1".to_vec()));
+    assert_eq!(request("PUT", "/keys/key1", b"value2").0, 204);
+    assert_eq!(request("GET", "/keys/key1", b""), (200, b"value2".to_vec()));
+
+    // percent-encoded binary keys
+    assert_eq!(request("PUT", "/keys/a%2Fb%00%FF", b"\xff").0, 204);
+    assert_eq!(
+        request("GET", "/keys/a%2Fb%00%FF", b""),
+        (200, b"\xff".to_vec())
+    );
+
+    // not found
+    let (status, body) = request("GET", "/keys/nope", b"");
+    assert_eq!(status, 404);
+    assert!(json_body(&body)["error"].is_string());
+    assert_eq!(request("DELETE", "/keys/nope", b"").0, 404);
+    assert_eq!(request("DELETE", "/keys/key1", b"").0, 204);
+    assert_eq!(request("GET", "/keys/key1", b"").0, 404);
+    assert_eq!(request("GET", "/nope", b"").0, 404);
+    assert_eq!(request("POST", "/keys/key1", b"").0, 405);
+
+    // scans
+    for i in 0..5 {
+        let target = format!("/keys/user%3A{}", i);
+        assert_eq!(request("PUT", &target, format!("v{}", i).as_bytes()).0, 204);
+    }
+    let (status, body) = request("GET", "/keys?prefix=user%3A&limit=3", b"");
+    assert_eq!(status, 200);
+    assert_eq!(
+        json_body(&body),
+        json!([
+            {"key": "user:0", "value": "v0"},
+            {"key": "user:1", "value": "v1"},
+            {"key": "user:2", "value": "v2"},
+        ])
+    );
+    let (_, body) = request("GET", "/keys?prefix=a", b"");
+    assert_eq!(
+        json_body(&body),
+        json!([{"key": [97, 47, 98, 0, 255], "value": [255]}])
+    );
+    let (_, body) = request("GET", "/keys", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 6);
+    assert_eq!(request("GET", "/keys?limit=x", b"").0, 400);
+
+    stop_server(server);
+}
+
+// Keys and values over the limits of the server should be refused
+#[test]
+fn http_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let http_addr = "127.0.0.1:4052";
+    let extra = ["--max-key-size", "16", "--max-value-size", "32"];
+    let server = start_server_with(&temp_dir, "127.0.0.1:4051", http_addr, &extra);
+    let put = |target: &str, val: &[u8]| {
+        let head = format!("Content-Length: {}\r\n", val.len());
+        request_to(http_addr, "PUT", target, &head, val)
+    };
+
+    assert_eq!(put("/keys/key", &[b'v'; 32]).0, 204);
+    let (status, body) = put(&format!("/keys/{}", "k".repeat(17)), b"v");
+    assert_eq!(status, 414);
+    assert!(json_body(&body)["error"].is_string());
+    let target = format!("/keys/{}", "k".repeat(17));
+    assert_eq!(request_to(http_addr, "GET", &target, "", b"").0, 414);
+    assert_eq!(put("/keys/key", &[b'v'; 33]).0, 413);
+
+    // the announced length is refused before the body is read
+    let head = "Content-Length: 1000000000\r\n";
+    assert_eq!(request_to(http_addr, "PUT", "/keys/key", head, b"").0, 413);
+    // so is a chunked body once it grows over the limit
+    let head = "Transfer-Encoding: chunked\r\n";
+    let body = b"14\r\nvvvvvvvvvvvvvvvvvvvv\r\n14\r\nvvvvvvvvvvvvvvvvvvvv\r\n0\r\n\r\n";
+    assert_eq!(request_to(http_addr, "PUT", "/keys/key", head, body).0, 413);
+
+    let (status, body) = request_to(http_addr, "GET", "/keys/key", "", b"");
+    assert_eq!((status, body), (200, vec![b'v'; 32]));
+    stop_server(server);
+}
+
+// A scan should never reply more than a page, whatever its limit
+#[test]
+fn http_scan_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let (addr, http_addr) = ("127.0.0.1:4054", "127.0.0.1:4055");
+    let server = start_server_with(&temp_dir, addr, http_addr, &[]);
+    let mut client = Client::connect(addr).unwrap();
+    for i in 0..1001 {
+        let key = format!("key{:04}", i).into_bytes();
+        client.set(key, b"v".to_vec()).unwrap();
+    }
+
+    let target = format!("/keys?prefix=key&limit={}", u64::MAX);
+    let (status, body) = request_to(http_addr, "GET", &target, "", b"");
+    assert_eq!(status, 200);
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 1000);
+    let (_, body) = request_to(http_addr, "GET", "/keys?limit=3", "", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 3);
+
+    stop_server(server);
+}
@@ -0,0 +1,817 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MyErr, Result, SyncPolicy, WriteBatch};
+use serde_json::json;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open_store(dir: &TempDir) -> Result<KvStore<SharedQueueThreadPool>> {
+    let opts = KvStoreOptions::new()
+        .segment_size(1024)
+        .compact_threshold(2048)
+        .compact_check(Duration::from_millis(100));
+    KvStore::open(dir.path(), SharedQueueThreadPool::new(4)?, opts)
+}
+
+// Should get previously stored value
+#[tokio::test]
+async fn get_stored_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+
+    store.clone().set("key1".into(), "value1".into()).await?;
+    store.clone().set("key2".into(), "value2".into()).await?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value2".into())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value2".into())
+    );
+    Ok(())
+}
+
+// Should overwrite existent value
+#[tokio::test]
+async fn overwrite_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+
+    store.clone().set("key1".into(), "value1".into()).await?;
+    store.clone().set("key1".into(), "value2".into()).await?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value2".into())
+    );
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value2".into())
+    );
+    Ok(())
+}
+
+// Should remove existent key and report non-existent one
+#[tokio::test]
+async fn remove_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+
+    store.clone().set("key1".into(), "value1".into()).await?;
+    assert!(store.clone().remove("key1".into()).await.is_ok());
+    assert_eq!(store.clone().get("key1".into()).await?, None);
+    assert!(store.clone().remove("key1".into()).await.is_err());
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("key1".into()).await?, None);
+    Ok(())
+}
+
+// Compaction reads segments positionally, values must survive it
+#[tokio::test]
+async fn compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+
+    for iter in 0..100 {
+        for key_id in 0..20 {
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
+            store.clone().set(key, value).await?;
+        }
+    }
+    // wait for background compactor
+    thread::sleep(Duration::from_secs(1));
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.clone().get(key).await?, Some("99".into()));
+    }
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(store.clone().get(key).await?, Some("99".into()));
+    }
+    Ok(())
+}
+
+// Compaction should roll over to a new output at the segment size
+#[tokio::test]
+async fn compaction_output_rollover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        store
+            .clone()
+            .set(key, format!("value{}", key_id).into_bytes())
+            .await?;
+        let churn = format!("{:0>100}", key_id).into_bytes();
+        store.clone().set("churn".into(), churn).await?;
+    }
+    // wait for background compactor
+    thread::sleep(Duration::from_secs(1));
+    drop(store);
+
+    let segs = segments(&temp_dir);
+    assert!(fs::metadata(temp_dir.path().join("MANIFEST")).is_ok());
+    for seg in &segs {
+        assert!(fs::metadata(seg)?.len() < 1024 + 128);
+    }
+    let store = open_store(&temp_dir)?;
+    for key_id in 0..100 {
+        let key = format!("key{}", key_id).into_bytes();
+        assert_eq!(
+            store.clone().get(key).await?,
+            Some(format!("value{}", key_id).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Compaction should leave hint files, which are used to load the index
+#[tokio::test]
+async fn load_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    // live records are mixed with stale ones in every segment
+    for key_id in 0..20 {
+        let key = format!("key{}", key_id).into_bytes();
+        store
+            .clone()
+            .set(key, format!("value{}", key_id).into_bytes())
+            .await?;
+        let churn = format!("{:0>100}", key_id).into_bytes();
+        store.clone().set("churn".into(), churn).await?;
+    }
+    store.clone().remove("key0".into()).await?;
+    // wait for background compactor
+    thread::sleep(Duration::from_secs(1));
+    drop(store);
+
+    let hints: Vec<PathBuf> = fs::read_dir(temp_dir.path())?
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "hint"))
+        .collect();
+    assert!(!hints.is_empty());
+    for corrupt in [false, true] {
+        if corrupt {
+            // an invalid hint file falls back to replay the segment
+            for h in &hints {
+                fs::write(h, b"garbage")?;
+            }
+        }
+        let store = open_store(&temp_dir)?;
+        assert_eq!(store.clone().get("key0".into()).await?, None);
+        for key_id in 1..20 {
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(
+                store.clone().get(key).await?,
+                Some(format!("value{}", key_id).into_bytes())
+            );
+        }
+        assert_eq!(
+            store.clone().get("churn".into()).await?,
+            Some(format!("{:0>100}", 19).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Segments written in the legacy json format should still be readable
+#[tokio::test]
+async fn read_legacy_json_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut legacy = Vec::new();
+    for (key, val, is_del) in [
+        ("key1", "value1", false),
+        ("key2", "value2", false),
+        ("key1", "", true),
+    ] {
+        let ent = json!({ "key": key, "val": val, "is_del": is_del }).to_string();
+        legacy.extend_from_slice(&(ent.len() as u32).to_be_bytes());
+        legacy.extend_from_slice(ent.as_bytes());
+    }
+    fs::write(temp_dir.path().join("000000001.kvs"), legacy)?;
+
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("key1".into()).await?, None);
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value2".into())
+    );
+    store.clone().set("key3".into(), "value3".into()).await?;
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value2".into())
+    );
+    assert_eq!(
+        store.clone().get("key3".into()).await?,
+        Some("value3".into())
+    );
+    Ok(())
+}
+
+fn segments(dir: &TempDir) -> Vec<PathBuf> {
+    let mut segs: Vec<PathBuf> = fs::read_dir(dir.path())
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|ext| ext == "kvs"))
+        .collect();
+    segs.sort();
+    segs
+}
+
+// A torn record at the tail of the newest segment should be truncated away
+#[tokio::test]
+async fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+    store.clone().set("key2".into(), "value2".into()).await?;
+    drop(store);
+
+    let newest = segments(&temp_dir).pop().unwrap();
+    let len = fs::metadata(&newest)?.len();
+    let mut file = OpenOptions::new().append(true).open(&newest)?;
+    file.write_all(&[0x81, 0, 0, 0, 0, 4, 0, 0])?;
+    drop(file);
+
+    let store = open_store(&temp_dir)?;
+    assert_eq!(fs::metadata(&newest)?.len(), len);
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value2".into())
+    );
+    store.clone().set("key3".into(), "value3".into()).await?;
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(
+        store.clone().get("key3".into()).await?,
+        Some("value3".into())
+    );
+    Ok(())
+}
+
+// Corruption in an older segment should be reported rather than repaired
+#[tokio::test]
+async fn detect_corrupted_segment() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key2".into(), "value2".into()).await?;
+    drop(store);
+
+    let oldest = segments(&temp_dir).remove(0);
+    let mut bytes = fs::read(&oldest)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(&oldest, bytes)?;
+
+    match open_store(&temp_dir) {
+        Err(err) => assert!(matches!(
+            err.downcast_ref::<MyErr>(),
+            Some(MyErr::Corrupted(_, 0))
+        )),
+        Ok(_) => panic!("corrupted segment is not detected"),
+    }
+    Ok(())
+}
+
+// Every sync policy should persist concurrent writes
+#[tokio::test]
+async fn sync_policies() -> Result<()> {
+    for sync in [
+        SyncPolicy::Always,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        SyncPolicy::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let opts = KvStoreOptions::new().segment_size(1024).sync(sync);
+        let store = KvStore::open(temp_dir.path(), SharedQueueThreadPool::new(4)?, opts)?;
+        let writes = (0..100).map(|i| {
+            let store = store.clone();
+            tokio::spawn(async move {
+                store
+                    .set(format!("key{}", i).into(), format!("{}", i).into())
+                    .await
+            })
+        });
+        for w in writes.collect::<Vec<_>>() {
+            w.await??;
+        }
+
+        drop(store);
+        let store = open_store(&temp_dir)?;
+        for i in 0..100 {
+            assert_eq!(
+                store.clone().get(format!("key{}", i).into()).await?,
+                Some(format!("{}", i).into_bytes())
+            );
+        }
+    }
+    Ok(())
+}
+
+// Files left by an interrupted compaction are not in the manifest, they must
+// be removed rather than replayed
+#[tokio::test]
+async fn remove_files_outside_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value2".into()).await?;
+    drop(store);
+
+    // a stray output holding a stale value, with a higher id than its source
+    let stray = temp_dir.path().join("000000099.kvs");
+    fs::copy(segments(&temp_dir).remove(0), &stray)?;
+    let garbage = [
+        stray,
+        temp_dir.path().join("000000099.hint"),
+        temp_dir.path().join("MANIFEST.tmp"),
+    ];
+    fs::write(&garbage[1], b"garbage")?;
+    fs::write(&garbage[2], b"garbage")?;
+
+    let store = open_store(&temp_dir)?;
+    for path in &garbage {
+        assert!(!path.exists());
+    }
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value2".into())
+    );
+    Ok(())
+}
+
+// An edit torn by crash at the tail of the manifest is dropped, corruption
+// before the tail is reported
+#[tokio::test]
+async fn recover_torn_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+    drop(store);
+
+    let manifest = temp_dir.path().join("MANIFEST");
+    let len = fs::metadata(&manifest)?.len();
+    let mut file = OpenOptions::new().append(true).open(&manifest)?;
+    file.write_all(&[0, 0, 0, 9, 1, 2])?;
+    drop(file);
+
+    let store = open_store(&temp_dir)?;
+    assert!(fs::metadata(&manifest)?.len() > len);
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    drop(store);
+
+    let mut bytes = fs::read(&manifest)?;
+    bytes[8] ^= 0xff;
+    fs::write(&manifest, bytes)?;
+    match open_store(&temp_dir) {
+        Err(err) => assert!(matches!(
+            err.downcast_ref::<MyErr>(),
+            Some(MyErr::CorruptedManifest)
+        )),
+        Ok(_) => panic!("corrupted manifest is not detected"),
+    }
+    Ok(())
+}
+
+// Older releases could crash after removing compaction sources but before
+// renaming the `compacting` file, its records must not be lost
+#[tokio::test]
+async fn adopt_legacy_compacting_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let legacy = |records: &[(&str, &str)]| {
+        let mut buf = Vec::new();
+        for (key, val) in records {
+            let ent = json!({ "key": key, "val": val, "is_del": false }).to_string();
+            buf.extend_from_slice(&(ent.len() as u32).to_be_bytes());
+            buf.extend_from_slice(ent.as_bytes());
+        }
+        buf
+    };
+    let mut compacting = legacy(&[("key1", "value1"), ("key2", "value2")]);
+    // never synced, so the tail may be torn
+    compacting.extend_from_slice(&[0, 0, 1]);
+    fs::write(temp_dir.path().join("compacting"), compacting)?;
+    fs::write(temp_dir.path().join("compacting.hint"), b"garbage")?;
+    fs::write(
+        temp_dir.path().join("000000002.kvs"),
+        legacy(&[("key2", "value3")]),
+    )?;
+
+    let store = open_store(&temp_dir)?;
+    assert!(!temp_dir.path().join("compacting").exists());
+    assert!(!temp_dir.path().join("compacting.hint").exists());
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value3".into())
+    );
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(
+        store.clone().get("key2".into()).await?,
+        Some("value3".into())
+    );
+    Ok(())
+}
+
+// Scans should list live pairs in key order, within range or prefix
+#[tokio::test]
+async fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    for key in ["user:1:b", "user:2:a", "user:1:a", "order:1", "user:10"] {
+        store
+            .clone()
+            .set(key.into(), format!("{}!", key).into())
+            .await?;
+    }
+    store.clone().remove("user:1:b".into()).await?;
+    let pair = |key: &str| (key.as_bytes().to_vec(), format!("{}!", key).into_bytes());
+
+    assert_eq!(
+        store.clone().scan_prefix("user:1".into(), 10).await?,
+        vec![pair("user:10"), pair("user:1:a")]
+    );
+    assert_eq!(
+        store.clone().scan_prefix("user:1:".into(), 10).await?,
+        vec![pair("user:1:a")]
+    );
+    assert_eq!(
+        store
+            .clone()
+            .scan("p".into(), Some("user:2".into()), 10)
+            .await?,
+        vec![pair("user:10"), pair("user:1:a")]
+    );
+    assert_eq!(
+        store.clone().scan("".into(), None, 2).await?,
+        vec![pair("order:1"), pair("user:10")]
+    );
+    assert!(store
+        .clone()
+        .scan("user:2".into(), Some("user:1".into()), 10)
+        .await?
+        .is_empty());
+
+    // the ordered key set is rebuilt on open
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().scan("".into(), None, 10).await?,
+        vec![
+            pair("order:1"),
+            pair("user:10"),
+            pair("user:1:a"),
+            pair("user:2:a")
+        ]
+    );
+    Ok(())
+}
+
+// Arbitrary bytes should round-trip, including after reopen
+#[tokio::test]
+async fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+        (b" key\n1 ".to_vec(), b"\tvalue\nwith lines \n".to_vec()),
+        (vec![0xff, 0, 0xfe], vec![0x80, 0, 0xc3]),
+        (b"empty".to_vec(), Vec::new()),
+    ];
+    let mut store = open_store(&temp_dir)?;
+    for (k, v) in &pairs {
+        store.clone().set(k.clone(), v.clone()).await?;
+    }
+    for _ in 0..2 {
+        for (k, v) in &pairs {
+            assert_eq!(store.clone().get(k.clone()).await?.as_ref(), Some(v));
+        }
+        assert_eq!(
+            store.clone().scan_prefix(vec![0xff], 10).await?,
+            vec![pairs[1].clone()]
+        );
+        drop(store);
+        store = open_store(&temp_dir)?;
+    }
+    Ok(())
+}
+
+// Expired keys should be invisible, and a plain set should clear an expiry
+#[tokio::test]
+async fn expire_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    let ttl = Duration::from_millis(300);
+    store
+        .clone()
+        .set_with_ttl("short".into(), "value".into(), ttl)
+        .await?;
+    store
+        .clone()
+        .set_with_ttl("long".into(), "value".into(), Duration::from_secs(3600))
+        .await?;
+    store
+        .clone()
+        .set_with_ttl("cleared".into(), "old".into(), ttl)
+        .await?;
+    store.clone().set("cleared".into(), "new".into()).await?;
+    assert_eq!(
+        store.clone().get("short".into()).await?,
+        Some("value".into())
+    );
+    assert_eq!(store.clone().scan(Vec::new(), None, 10).await?.len(), 3);
+
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.clone().get("short".into()).await?, None);
+    assert_eq!(
+        store.clone().scan(Vec::new(), None, 10).await?,
+        vec![
+            ("cleared".into(), "new".into()),
+            ("long".into(), "value".into())
+        ]
+    );
+    match store.clone().remove("short".into()).await {
+        Err(e) => assert!(matches!(e.downcast_ref(), Some(MyErr::KeyNotFound))),
+        Ok(()) => panic!("expired key removed"),
+    }
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("short".into()).await?, None);
+    assert_eq!(
+        store.clone().get("long".into()).await?,
+        Some("value".into())
+    );
+    assert_eq!(
+        store.clone().get("cleared".into()).await?,
+        Some("new".into())
+    );
+    Ok(())
+}
+
+// Compaction should drop expired records, without bringing back the older
+// values they replaced
+#[tokio::test]
+async fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    let churn = |store: KvStore<SharedQueueThreadPool>| async move {
+        for i in 0..30 {
+            let val = format!("{:0>100}", i).into_bytes();
+            store.clone().set("churn".into(), val).await?;
+        }
+        Result::Ok(())
+    };
+    // the segment of the old value is kept by compaction
+    store.clone().set("session".into(), "old".into()).await?;
+    for i in 0..10 {
+        let key = format!("perm{}", i).into_bytes();
+        store
+            .clone()
+            .set(key, format!("{:0>100}", i).into_bytes())
+            .await?;
+    }
+    churn(store.clone()).await?;
+    let ttl = Duration::from_millis(300);
+    store
+        .clone()
+        .set_with_ttl("session".into(), "new".into(), ttl)
+        .await?;
+    for i in 0..20 {
+        let key = format!("temp{}", i).into_bytes();
+        let val = format!("expiring{:0>100}", i).into_bytes();
+        store.clone().set_with_ttl(key, val, ttl).await?;
+    }
+    store.clone().set("live".into(), "value".into()).await?;
+    churn(store.clone()).await?;
+    // wait for expiry and background compactor
+    thread::sleep(Duration::from_secs(1));
+
+    for seg in segments(&temp_dir) {
+        let bytes = fs::read(seg)?;
+        assert!(!bytes.windows(8).any(|w| w == b"expiring"));
+    }
+    assert_eq!(store.clone().get("session".into()).await?, None);
+    assert_eq!(store.clone().get("temp0".into()).await?, None);
+    assert_eq!(
+        store.clone().get("live".into()).await?,
+        Some("value".into())
+    );
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("session".into()).await?, None);
+    assert_eq!(store.clone().scan_prefix("temp".into(), 10).await?, vec![]);
+    assert_eq!(
+        store.clone().get("live".into()).await?,
+        Some("value".into())
+    );
+    assert_eq!(
+        store.clone().scan_prefix("perm".into(), 20).await?.len(),
+        10
+    );
+    Ok(())
+}
+
+// Compare and swap should only write over the expected value
+#[tokio::test]
+async fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+
+    assert!(
+        store
+            .clone()
+            .set_if_absent("key".into(), "v1".into())
+            .await?
+    );
+    assert!(
+        !store
+            .clone()
+            .set_if_absent("key".into(), "v2".into())
+            .await?
+    );
+    let cas = |expected: Option<&str>, new: Option<&str>| {
+        let (expected, new) = (expected.map(Vec::from), new.map(Vec::from));
+        store.clone().compare_and_swap("key".into(), expected, new)
+    };
+    assert!(!cas(Some("v2"), Some("v3")).await?);
+    assert!(!cas(None, Some("v3")).await?);
+    assert!(cas(Some("v1"), Some("v3")).await?);
+    assert_eq!(store.clone().get("key".into()).await?, Some("v3".into()));
+    assert!(cas(Some("v3"), None).await?);
+    assert_eq!(store.clone().get("key".into()).await?, None);
+    assert!(cas(None, None).await?);
+    assert!(!cas(Some("v3"), None).await?);
+
+    // an expired value compares as absent, and a swap clears the expiry
+    store
+        .clone()
+        .set_with_ttl("key".into(), "v4".into(), Duration::from_millis(200))
+        .await?;
+    assert!(!cas(None, Some("v5")).await?);
+    thread::sleep(Duration::from_millis(300));
+    assert!(!cas(Some("v4"), Some("v5")).await?);
+    assert!(
+        store
+            .clone()
+            .set_if_absent("key".into(), "v5".into())
+            .await?
+    );
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("key".into()).await?, Some("v5".into()));
+    Ok(())
+}
+
+// Writes of a batch should be applied in order and survive reopen
+#[tokio::test]
+async fn apply_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+
+    let mut batch = WriteBatch::new();
+    batch.set("key2".into(), "value2".into());
+    batch.remove("key1".into());
+    batch.set("key3".into(), "old".into());
+    batch.set("key3".into(), "value3".into());
+    batch.remove("absent".into());
+    store.clone().apply_batch(batch).await?;
+    store.clone().apply_batch(WriteBatch::new()).await?;
+
+    let check = |store: KvStore<SharedQueueThreadPool>| async move {
+        assert_eq!(store.clone().get("key1".into()).await?, None);
+        assert_eq!(
+            store.clone().get("key2".into()).await?,
+            Some("value2".into())
+        );
+        assert_eq!(
+            store.clone().get("key3".into()).await?,
+            Some("value3".into())
+        );
+        assert_eq!(store.clone().scan_prefix("key".into(), 10).await?.len(), 2);
+        Result::Ok(())
+    };
+    check(store.clone()).await?;
+    drop(store);
+    check(open_store(&temp_dir)?).await
+}
+
+// A batch torn by crash should be dropped as a whole on reopen
+#[tokio::test]
+async fn drop_torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("key1".into(), "value1".into()).await?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2".into(), "value2".into());
+    batch.remove("key1".into());
+    batch.set("key3".into(), "value3".into());
+    store.clone().apply_batch(batch).await?;
+    drop(store);
+
+    // only the last write of the batch is torn
+    let newest = segments(&temp_dir).pop().unwrap();
+    let len = fs::metadata(&newest)?.len();
+    let file = OpenOptions::new().write(true).open(&newest)?;
+    file.set_len(len - 1)?;
+    drop(file);
+
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key1".into()).await?,
+        Some("value1".into())
+    );
+    assert_eq!(store.clone().get("key2".into()).await?, None);
+    assert_eq!(store.clone().get("key3".into()).await?, None);
+    store.clone().set("key4".into(), "value4".into()).await?;
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(
+        store.clone().get("key4".into()).await?,
+        Some("value4".into())
+    );
+    assert_eq!(store.clone().get("key2".into()).await?, None);
+    Ok(())
+}
+
+// Writes of batches should be kept by compaction like any other
+#[tokio::test]
+async fn compact_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    for iter in 0..50 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..10 {
+            let key = format!("key{}", key_id);
+            batch.set(key.into_bytes(), format!("{}", iter).into_bytes());
+        }
+        batch.remove("key0".into());
+        store.clone().apply_batch(batch).await?;
+    }
+    // wait for background compactor
+    thread::sleep(Duration::from_secs(1));
+    assert!(segments(&temp_dir).len() < 10);
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(store.clone().get("key0".into()).await?, None);
+    for key_id in 1..10 {
+        let key = format!("key{}", key_id);
+        assert_eq!(
+            store.clone().get(key.into_bytes()).await?,
+            Some("49".into())
+        );
+    }
+    Ok(())
+}
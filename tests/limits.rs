@@ -0,0 +1,235 @@
+mod common;
+
+use assert_cmd::cargo::CommandCargoExt;
+use common::{serve, wait_for_server};
+use kvs::client::{AsyncClient, Client, ClientError};
+use kvs::protocol::{self, Command, Request, Status};
+use kvs::resp::{self, Value};
+use kvs::server::{Protocol, ServerOptions};
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Command as Process;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn assert_bad_request<T: std::fmt::Debug>(result: Result<T, ClientError>, what: &str) {
+    match result {
+        Err(ClientError::Server(Status::BadRequest, msg)) => assert!(msg.contains(what), "{}", msg),
+        other => panic!("expected bad request, got {:?}", other),
+    }
+}
+
+// Keys, values and requests over the configured limits should be refused
+#[test]
+fn size_limits() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4037";
+    let opts = ServerOptions::new()
+        .max_key_len(32)
+        .max_val_len(16)
+        .max_request_len(44);
+    serve(&temp_dir, addr, opts);
+
+    let mut client = Client::connect(addr).unwrap();
+    client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert_bad_request(client.get(vec![b'k'; 33]), "key of 33 bytes");
+    assert_bad_request(
+        client.set(b"k".to_vec(), vec![b'v'; 17]),
+        "value of 17 bytes",
+    );
+    // the connection is closed after a frame too large
+    let err = client
+        .set(vec![b'k'; 30], vec![b'v'; 16])
+        .expect_err("request should be too large");
+    assert!(matches!(
+        err,
+        ClientError::Server(Status::BadRequest, _) | ClientError::Protocol(_)
+    ));
+
+    let mut client = Client::connect(addr).unwrap();
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some(b"value".to_vec())
+    );
+
+    // a legacy value is bounded by the request limit too
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = vec![protocol::OP_SET];
+    req.extend_from_slice(&32_u32.to_be_bytes());
+    req.extend_from_slice(&[b'k'; 32]);
+    req.extend_from_slice(&16_u32.to_be_bytes());
+    stream.write_all(&req).unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    assert_eq!(resp[0], protocol::GET_ERR);
+}
+
+// A RESP command over the request limit should be refused before read
+#[test]
+fn resp_request_limit() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4038";
+    let opts = ServerOptions::new()
+        .protocol(Protocol::Resp)
+        .max_request_len(1024);
+    serve(&temp_dir, addr, opts);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100000000\r\n")
+        .unwrap();
+    let mut reader = BufReader::new(stream);
+    match resp::read_value(&mut reader).unwrap() {
+        Value::Error(msg) => assert!(msg.contains("too big request"), "{}", msg),
+        v => panic!("unexpected reply {:?}", v),
+    }
+}
+
+// Connections over the limit should be refused with an error in their protocol
+#[test]
+fn max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4039";
+    serve(&temp_dir, addr, ServerOptions::new().max_connections(2));
+
+    let mut first = Client::connect(addr).unwrap();
+    first.ping().unwrap();
+    let mut second = Client::connect(addr).unwrap();
+    second.ping().unwrap();
+
+    let mut client = Client::connect(addr).unwrap();
+    match client.ping() {
+        Err(ClientError::Server(Status::Unavailable, msg)) => {
+            assert_eq!(msg, "Too many connections")
+        }
+        other => panic!("expected refusal, got {:?}", other),
+    }
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let refused = rt.block_on(AsyncClient::connect(addr));
+    assert!(matches!(
+        refused.err(),
+        Some(ClientError::Server(Status::Unavailable, _))
+    ));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(&[protocol::OP_PING]).unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    assert_eq!(resp[0], protocol::GET_ERR);
+    assert_eq!(&resp[5..], b"Too many connections");
+
+    // a slot is freed once a connection is closed
+    first.close().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    Client::connect(addr).unwrap().ping().unwrap();
+}
+
+// A RESP connection over the limit should get the error of Redis
+#[test]
+fn resp_max_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4040";
+    let opts = ServerOptions::new()
+        .protocol(Protocol::Resp)
+        .max_connections(1);
+    serve(&temp_dir, addr, opts);
+
+    let mut first = TcpStream::connect(addr).unwrap();
+    first.write_all(b"PING\r\n").unwrap();
+    let mut pong = [0; 7];
+    first.read_exact(&mut pong).unwrap();
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream);
+    assert_eq!(
+        resp::read_value(&mut reader).unwrap(),
+        Value::Error("ERR max number of clients reached".to_owned())
+    );
+}
+
+// Connections stuck receiving a request or sending responses should be closed
+#[test]
+fn read_write_timeouts() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4041";
+    let opts = ServerOptions::new()
+        .read_timeout(Duration::from_millis(300))
+        .write_timeout(Duration::from_millis(300));
+    serve(&temp_dir, addr, opts);
+
+    // idle connections are not closed by the read timeout
+    let mut client = Client::connect(addr).unwrap();
+    client
+        .set(b"key".to_vec(), vec![b'v'; 1024 * 1024])
+        .unwrap();
+    thread::sleep(Duration::from_millis(600));
+    client.ping().unwrap();
+
+    // half a request
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"?\0\0\0\x03ke").unwrap();
+    let start = Instant::now();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // responses never read
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut reqs = protocol::HANDSHAKE.to_vec();
+    reqs.push(protocol::VERSION);
+    for id in 0..200 {
+        let cmd = Command::Get {
+            key: b"key".to_vec(),
+        };
+        reqs.extend_from_slice(&Request { id, cmd }.encode());
+    }
+    stream.write_all(&reqs).unwrap();
+    thread::sleep(Duration::from_secs(2));
+    let mut received = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n,
+        }
+    }
+    assert!(received < 200 * 1024 * 1024);
+}
+
+// Running out of file descriptors while accepting should not stop the server
+#[test]
+fn accept_errors_survived() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4049";
+    let server = Process::cargo_bin("kvs-server").unwrap();
+    let cmd = format!("ulimit -n 64 && exec {:?} \"$@\"", server.get_program());
+    let mut child = Process::new("sh")
+        .args(["-c", &cmd, "sh", "--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    wait_for_server(addr);
+
+    let mut client = Client::connect(addr).unwrap();
+    client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    // far more connections than descriptors the server may open
+    let flood: Vec<_> = (0..120)
+        .filter_map(|_| TcpStream::connect(addr).ok())
+        .collect();
+    thread::sleep(Duration::from_millis(500));
+    assert!(child.try_wait().unwrap().is_none(), "server exited");
+    drop(flood);
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some(b"value".to_vec())
+    );
+    let mut client = Client::connect(addr).unwrap();
+    client.ping().unwrap();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait server");
+}
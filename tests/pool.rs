@@ -0,0 +1,142 @@
+mod common;
+
+use common::{serve, start_server, stop_server};
+use kvs::client::{AsyncClientPool, ClientPool, PoolOptions};
+use kvs::server::ServerOptions;
+use kvs::Result;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Threads sharing a pool should never open more than its max size
+#[test]
+fn pool_shared_by_threads() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let pool = Arc::new(ClientPool::new(addr, PoolOptions::new().max_size(4)));
+    let handles = (0..16)
+        .map(|t| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", t, i).into_bytes();
+                    pool.set(key.clone(), b"value".to_vec()).unwrap();
+                    assert_eq!(pool.get(key).unwrap(), Some(b"value".to_vec()));
+                    assert!(pool.size() <= 4);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert!(pool.remove(b"key0-0".to_vec()).unwrap());
+    assert!(!pool.remove(b"key0-0".to_vec()).unwrap());
+    assert!(pool.size() <= 4);
+
+    stop_server(server);
+}
+
+// Connections broken by a server restart should be replaced transparently
+#[test]
+fn pool_reconnects() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4026";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let opts = PoolOptions::new()
+        .ping_interval(Duration::ZERO)
+        .retry(5, Duration::from_millis(100));
+    let pool = ClientPool::new(addr, opts);
+    pool.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    assert_eq!(pool.size(), 1);
+
+    stop_server(server);
+    let server = start_server(&temp_dir, addr, &[]);
+    assert_eq!(pool.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    assert_eq!(pool.size(), 1);
+
+    // the server comes back while retrying
+    stop_server(server);
+    let dir = temp_dir.path().to_owned();
+    let restart = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(200));
+        start_server(dir, addr, &[])
+    });
+    assert_eq!(pool.get(b"key".to_vec()).unwrap(), Some(b"value".to_vec()));
+    stop_server(restart.join().unwrap());
+}
+
+// Tasks sharing a pool should never open more than its max size
+#[tokio::test]
+async fn async_pool_shared_by_tasks() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4027";
+    serve(&temp_dir, addr, ServerOptions::new());
+
+    let pool = Arc::new(AsyncClientPool::new(addr, PoolOptions::new().max_size(3)));
+    let tasks = (0..10)
+        .map(|t| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for i in 0..20 {
+                    let key = format!("key{}-{}", t, i).into_bytes();
+                    pool.set(key.clone(), b"value".to_vec()).await.unwrap();
+                    assert_eq!(pool.get(key).await.unwrap(), Some(b"value".to_vec()));
+                    assert!(pool.size() <= 3);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    assert!(pool.remove(b"key0-0".to_vec()).await?);
+    assert!(pool.size() <= 3);
+
+    // a checked out connection is usable directly
+    let mut client = pool.checkout().await?;
+    client.ping().await?;
+    Ok(())
+}
+
+// Idle connections should be closed once expired, even if none is taken
+#[test]
+fn idle_connections_expire() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4053";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let opts = PoolOptions::new().idle_timeout(Duration::from_millis(200));
+    let pool = ClientPool::new(addr, opts);
+    let (first, second) = (pool.checkout().unwrap(), pool.checkout().unwrap());
+    assert_eq!(pool.size(), 2);
+    drop(first);
+    thread::sleep(Duration::from_millis(300));
+    drop(second);
+    assert_eq!(pool.size(), 1);
+
+    stop_server(server);
+}
+
+// Idle connections of an async pool should be closed once expired, even if
+// none is taken
+#[tokio::test]
+async fn async_idle_connections_expire() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4056";
+    serve(&temp_dir, addr, ServerOptions::new());
+
+    let opts = PoolOptions::new().idle_timeout(Duration::from_millis(200));
+    let pool = AsyncClientPool::new(addr, opts);
+    let (first, second) = (pool.checkout().await?, pool.checkout().await?);
+    assert_eq!(pool.size(), 2);
+    drop(first);
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    drop(second);
+    assert_eq!(pool.size(), 1);
+    Ok(())
+}
@@ -0,0 +1,403 @@
+mod common;
+
+use common::{start_server, stop_server};
+use kvs::client::{Client, ClientError, ClientOptions};
+use kvs::protocol::{self, Command, Reply, Request, Response, Status};
+use kvs::WriteBatch;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn field(buf: &[u8]) -> Vec<u8> {
+    let mut f = (buf.len() as u32).to_be_bytes().to_vec();
+    f.extend_from_slice(buf);
+    f
+}
+
+// Every request and response should decode to what is encoded
+#[test]
+fn v2_codec_round_trip() {
+    let mut batch = WriteBatch::new();
+    batch.set(b"k".to_vec(), Vec::new());
+    batch.remove(vec![0, 0xff]);
+    let cmds = vec![
+        Command::Get { key: b"k".to_vec() },
+        Command::Set {
+            key: vec![0, 0xff],
+            val: Vec::new(),
+        },
+        Command::SetWithTtl {
+            key: b"k".to_vec(),
+            val: b"v".to_vec(),
+            ttl: Duration::from_millis(1500),
+        },
+        Command::Remove { key: Vec::new() },
+        Command::CompareAndSwap {
+            key: b"k".to_vec(),
+            expected: None,
+            new: Some(Vec::new()),
+        },
+        Command::CompareAndSwap {
+            key: b"k".to_vec(),
+            expected: Some(b"v".to_vec()),
+            new: None,
+        },
+        Command::Batch { batch },
+        Command::Batch {
+            batch: WriteBatch::new(),
+        },
+        Command::Scan {
+            start: b"a".to_vec(),
+            end: Some(b"b".to_vec()),
+            limit: 10,
+        },
+        Command::Scan {
+            start: Vec::new(),
+            end: None,
+            limit: u64::MAX,
+        },
+        Command::ScanPrefix {
+            prefix: b"p".to_vec(),
+            limit: 1,
+        },
+        Command::Close,
+        Command::Ping,
+    ];
+    for (id, cmd) in cmds.into_iter().enumerate() {
+        let req = Request { id: id as u32, cmd };
+        let frame = req.encode();
+        assert_eq!(Request::decode(&frame[4..]).unwrap(), req);
+    }
+
+    let replies = vec![
+        Reply::Done,
+        Reply::Value(b"\nv ".to_vec()),
+        Reply::NotFound,
+        Reply::Conflict,
+        Reply::Pairs(vec![(b"k".to_vec(), b"v".to_vec()), (vec![0], Vec::new())]),
+        Reply::Error(Status::BadRequest, "bad".to_owned()),
+        Reply::Error(Status::Internal, "internal".to_owned()),
+    ];
+    for reply in replies {
+        let resp = Response { id: 7, reply };
+        let frame = resp.encode();
+        assert_eq!(Response::decode(&frame[4..]).unwrap(), resp);
+    }
+
+    // truncated and trailing bytes are rejected
+    let frame = Request {
+        id: 1,
+        cmd: Command::Get {
+            key: b"key".to_vec(),
+        },
+    }
+    .encode();
+    assert!(Request::decode(&frame[4..frame.len() - 1]).is_err());
+    let mut long = frame[4..].to_vec();
+    long.push(0);
+    assert!(Request::decode(&long).is_err());
+}
+
+// Legacy and v2 clients should be served side by side
+#[test]
+fn legacy_and_v2_side_by_side() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    // legacy set, v2 get
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = vec![protocol::OP_SET];
+    req.extend(field(b"key1"));
+    req.extend(field(b"value1"));
+    stream.write_all(&req).unwrap();
+    let mut status = [0; 1];
+    stream.read_exact(&mut status).unwrap();
+    assert_eq!(status[0], protocol::RES_OK);
+    let mut client = Client::new(TcpStream::connect(addr).unwrap());
+    assert_eq!(
+        client.get(b"key1".to_vec()).unwrap(),
+        Some(b"value1".to_vec())
+    );
+
+    // v2 set, legacy get
+    let mut client = Client::new(TcpStream::connect(addr).unwrap());
+    client.set(b"key2".to_vec(), b"value2".to_vec()).unwrap();
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = vec![protocol::OP_GET];
+    req.extend(field(b"key2"));
+    stream.write_all(&req).unwrap();
+    let mut expect = vec![protocol::GET_VAL];
+    expect.extend(field(b"value2"));
+    let mut resp = vec![0; expect.len()];
+    stream.read_exact(&mut resp).unwrap();
+    assert_eq!(resp, expect);
+
+    // legacy compare and swap, with optional fields
+    for (expected, status) in [
+        (&b"other"[..], protocol::RES_CONFLICT),
+        (b"value2", protocol::RES_OK),
+    ] {
+        let mut req = vec![protocol::OP_CAS];
+        req.extend(field(b"key2"));
+        req.push(1);
+        req.extend(field(expected));
+        req.push(0);
+        stream.write_all(&req).unwrap();
+        let mut resp = [0; 1];
+        stream.read_exact(&mut resp).unwrap();
+        assert_eq!(resp[0], status);
+    }
+    assert_eq!(client.get(b"key2".to_vec()).unwrap(), None);
+
+    // legacy batch, v2 batch
+    let mut req = vec![protocol::OP_BATCH];
+    req.extend_from_slice(&2_u32.to_be_bytes());
+    req.push(protocol::OP_SET);
+    req.extend(field(b"key3"));
+    req.extend(field(b"value3"));
+    req.push(protocol::OP_RM);
+    req.extend(field(b"key1"));
+    stream.write_all(&req).unwrap();
+    stream.read_exact(&mut status).unwrap();
+    assert_eq!(status[0], protocol::RES_OK);
+    assert_eq!(client.get(b"key1".to_vec()).unwrap(), None);
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key3".to_vec());
+    batch.set(b"key4".to_vec(), b"value4".to_vec());
+    client.apply_batch(batch).unwrap();
+    let mut req = vec![protocol::OP_GET];
+    req.extend(field(b"key4"));
+    stream.write_all(&req).unwrap();
+    let mut expect = vec![protocol::GET_VAL];
+    expect.extend(field(b"value4"));
+    let mut resp = vec![0; expect.len()];
+    stream.read_exact(&mut resp).unwrap();
+    assert_eq!(resp, expect);
+    assert_eq!(client.get(b"key3".to_vec()).unwrap(), None);
+
+    stop_server(server);
+}
+
+// A malformed v2 request should be answered with `BadRequest` and its id
+#[test]
+fn v2_bad_request() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = protocol::HANDSHAKE.to_vec();
+    req.push(protocol::VERSION);
+    // unknown op
+    req.extend_from_slice(&5_u32.to_be_bytes());
+    req.extend_from_slice(&42_u32.to_be_bytes());
+    req.push(b'#');
+    stream.write_all(&req).unwrap();
+
+    let mut ack = [0; 5];
+    stream.read_exact(&mut ack).unwrap();
+    assert_eq!(ack[..4], protocol::HANDSHAKE);
+    assert_eq!(ack[4], protocol::VERSION);
+    let frame = protocol::read_frame(&mut stream).unwrap().unwrap();
+    let resp = Response::decode(&frame).unwrap();
+    assert_eq!(resp.id, 42);
+    assert_eq!(resp.reply.status(), Status::BadRequest);
+
+    stop_server(server);
+}
+
+// A connection should serve requests until it is closed by `OP_CLOSE`
+#[test]
+fn persistent_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4013";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    // v2: many requests on one connection, including an abandoned scan
+    let mut client = Client::new(TcpStream::connect(addr).unwrap());
+    for i in 0..300 {
+        let key = format!("key{:03}", i).into_bytes();
+        client.set(key, b"v".to_vec()).unwrap();
+    }
+    let mut scan = client.scan_prefix(b"key".to_vec(), usize::MAX).unwrap();
+    assert!(scan.next().unwrap().is_ok());
+    drop(scan);
+    assert_eq!(client.get(b"key299".to_vec()).unwrap(), Some(b"v".to_vec()));
+    // scans dropped back to back, before pages of the first one are read
+    let mut scan = client.scan_prefix(b"key".to_vec(), usize::MAX).unwrap();
+    assert!(scan.next().unwrap().is_ok());
+    drop(scan);
+    drop(client.scan_prefix(b"key".to_vec(), usize::MAX).unwrap());
+    assert_eq!(client.get(b"key299".to_vec()).unwrap(), Some(b"v".to_vec()));
+    assert!(client.remove(b"key000".to_vec()).unwrap());
+    assert_eq!(client.get(b"key000".to_vec()).unwrap(), None);
+    client.close().unwrap();
+
+    // legacy: two requests then close
+    let mut stream = TcpStream::connect(addr).unwrap();
+    let mut req = vec![protocol::OP_SET];
+    req.extend(field(b"key"));
+    req.extend(field(b"value"));
+    req.push(protocol::OP_GET);
+    req.extend(field(b"key"));
+    req.push(protocol::OP_CLOSE);
+    stream.write_all(&req).unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    let mut expect = vec![protocol::RES_OK, protocol::GET_VAL];
+    expect.extend(field(b"value"));
+    expect.push(protocol::RES_OK);
+    assert_eq!(resp, expect);
+
+    stop_server(server);
+}
+
+// An idle connection should be closed by the server
+#[test]
+fn idle_timeout() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4014";
+    let server = start_server(&temp_dir, addr, &["--idle-timeout", "200"]);
+
+    let mut client = Client::new(TcpStream::connect(addr).unwrap());
+    client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.get(b"key".to_vec()).is_err());
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).unwrap();
+    assert!(resp.is_empty());
+
+    stop_server(server);
+}
+
+// Pipelined commands should be replied in order, even if there are more
+// requests and responses than socket buffers hold
+#[test]
+fn pipeline() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4015";
+    let server = start_server(&temp_dir, addr, &[]);
+
+    let mut client = Client::new(TcpStream::connect(addr).unwrap());
+    let replies = client
+        .pipeline()
+        .set(b"key".to_vec(), b"value".to_vec())
+        .get(b"key".to_vec())
+        .remove(b"key".to_vec())
+        .remove(b"key".to_vec())
+        .get(b"key".to_vec())
+        .execute()
+        .unwrap();
+    assert_eq!(
+        replies,
+        vec![
+            Reply::Done,
+            Reply::Value(b"value".to_vec()),
+            Reply::Done,
+            Reply::NotFound,
+            Reply::NotFound,
+        ]
+    );
+
+    let val = vec![b'v'; 1024];
+    let mut pipeline = client.pipeline();
+    for i in 0..5000 {
+        pipeline.set(format!("key{}", i).into_bytes(), val.clone());
+    }
+    for i in 0..5000 {
+        pipeline.get(format!("key{}", i).into_bytes());
+    }
+    let replies = pipeline.execute().unwrap();
+    assert_eq!(replies.len(), 10000);
+    assert!(replies[..5000].iter().all(|r| *r == Reply::Done));
+    assert!(replies[5000..]
+        .iter()
+        .all(|r| *r == Reply::Value(val.clone())));
+    assert!(pipeline.execute().unwrap().is_empty());
+
+    // the connection is still usable afterwards
+    assert_eq!(client.get(b"key42".to_vec()).unwrap(), Some(val));
+    client.close().unwrap();
+
+    stop_server(server);
+}
+
+// Client errors should tell server, protocol and transport failures apart
+#[test]
+fn client_errors() {
+    let listener = TcpListener::bind("127.0.0.1:4024").unwrap();
+    // answer each request with a scripted response then close
+    let server = thread::spawn(move || {
+        let (mut conn, _) = listener.accept().unwrap();
+        let mut hello = [0; 5];
+        conn.read_exact(&mut hello).unwrap();
+        conn.write_all(&hello).unwrap();
+        let replies = [
+            Some(Reply::Error(Status::Internal, "boom".to_owned())),
+            Some(Reply::Value(b"v".to_vec())),
+            None,
+        ];
+        for reply in replies {
+            let frame = protocol::read_frame(&mut conn).unwrap().unwrap();
+            let id = Request::decode(&frame).unwrap().id;
+            let resp = match reply {
+                Some(reply) => Response { id, reply }.encode(),
+                // unknown status code
+                None => vec![0, 0, 0, 5, 0, 0, 0, 0, 0xff],
+            };
+            conn.write_all(&resp).unwrap();
+        }
+    });
+
+    let mut client = Client::new(TcpStream::connect("127.0.0.1:4024").unwrap());
+    match client.set(b"k".to_vec(), b"v".to_vec()) {
+        Err(ClientError::Server(Status::Internal, msg)) => assert_eq!(msg, "boom"),
+        r => panic!("unexpected result {:?}", r),
+    }
+    // a value is not a reply to set
+    assert!(matches!(
+        client.set(b"k".to_vec(), b"v".to_vec()),
+        Err(ClientError::Protocol(_))
+    ));
+    assert!(matches!(
+        client.get(b"k".to_vec()),
+        Err(ClientError::Protocol(_))
+    ));
+    server.join().unwrap();
+    assert!(matches!(
+        client.get(b"k".to_vec()),
+        Err(ClientError::Transport(_))
+    ));
+}
+
+// A request not answered within the request timeout should fail with
+// `ClientError::Timeout`
+#[test]
+fn client_timeouts() {
+    // handshake is acked, requests are never answered
+    let listener = TcpListener::bind("127.0.0.1:4050").unwrap();
+    thread::spawn(move || {
+        let mut conns = Vec::new();
+        for conn in listener.incoming() {
+            let mut conn = conn.unwrap();
+            let mut hello = [0; 5];
+            conn.read_exact(&mut hello).unwrap();
+            conn.write_all(&hello).unwrap();
+            conns.push(conn);
+        }
+    });
+    let opts = ClientOptions::new().request_timeout(Duration::from_millis(100));
+    let mut client = Client::connect_with("127.0.0.1:4050", opts).unwrap();
+    let err = client
+        .get(b"key".to_vec())
+        .expect_err("request should time out");
+    assert!(matches!(err, ClientError::Timeout), "{:?}", err);
+}
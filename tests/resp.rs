@@ -0,0 +1,263 @@
+mod common;
+
+use common::stop_server;
+use kvs::resp::{self, Value};
+use std::collections::BTreeSet;
+use std::io::{BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::Child;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Start a server speaking RESP
+fn start_server(dir: &TempDir, addr: &str) -> Child {
+    common::start_server(dir, addr, &["--protocol", "resp"])
+}
+
+/// Minimal RESP client
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: &str) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        RespClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+    fn send(&mut self, args: &[&[u8]]) {
+        let cmd = Value::Array(args.iter().map(|a| Value::Bulk(a.to_vec())).collect());
+        self.writer.write_all(&cmd.encode()).unwrap();
+    }
+    fn recv(&mut self) -> Value {
+        resp::read_value(&mut self.reader).unwrap()
+    }
+    fn call(&mut self, args: &[&[u8]]) -> Value {
+        self.send(args);
+        self.recv()
+    }
+    /// Iterate a whole `SCAN` with options
+    fn scan_all(&mut self, opts: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut cursor = b"0".to_vec();
+        let mut keys = Vec::new();
+        loop {
+            let mut args: Vec<&[u8]> = vec![b"SCAN", &cursor];
+            args.extend_from_slice(opts);
+            let items = match self.call(&args) {
+                Value::Array(items) => items,
+                v => panic!("unexpected reply {:?}", v),
+            };
+            match &items[..] {
+                [Value::Bulk(next), Value::Array(page)] => {
+                    for k in page {
+                        match k {
+                            Value::Bulk(k) => keys.push(k.clone()),
+                            v => panic!("unexpected key {:?}", v),
+                        }
+                    }
+                    cursor = next.clone();
+                }
+                _ => panic!("unexpected reply {:?}", items),
+            }
+            if cursor == b"0" {
+                return keys;
+            }
+        }
+    }
+}
+
+fn ok() -> Value {
+    Value::Simple("OK".to_owned())
+}
+
+fn bulk(b: &[u8]) -> Value {
+    Value::Bulk(b.to_vec())
+}
+
+// Basic commands should map onto the engine
+#[test]
+fn resp_commands() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let server = start_server(&temp_dir, addr);
+    let mut cli = RespClient::connect(addr);
+
+    assert_eq!(cli.call(&[b"PING"]), Value::Simple("PONG".to_owned()));
+    assert_eq!(cli.call(&[b"ping", b"hi"]), bulk(b"hi"));
+    assert_eq!(cli.call(&[b"SET", b"key1", b"value1"]), ok());
+    assert_eq!(cli.call(&[b"set", b"key2", b"\r\n\0"]), ok());
+    assert_eq!(cli.call(&[b"GET", b"key1"]), bulk(b"value1"));
+    assert_eq!(cli.call(&[b"GET", b"key2"]), bulk(b"\r\n\0"));
+    assert_eq!(cli.call(&[b"GET", b"key3"]), Value::Nil);
+    assert_eq!(
+        cli.call(&[b"EXISTS", b"key1", b"key2", b"key3", b"key1"]),
+        Value::Integer(3)
+    );
+    assert_eq!(
+        cli.call(&[b"DEL", b"key1", b"key3", b"key2"]),
+        Value::Integer(2)
+    );
+    assert_eq!(cli.call(&[b"EXISTS", b"key1"]), Value::Integer(0));
+    assert_eq!(
+        cli.call(&[b"MSET", b"key1", b"a", b"key2", b"b", b"key1", b"c"]),
+        ok()
+    );
+    assert_eq!(cli.call(&[b"GET", b"key1"]), bulk(b"c"));
+    assert_eq!(cli.call(&[b"GET", b"key2"]), bulk(b"b"));
+    match cli.call(&[b"INFO"]) {
+        Value::Bulk(info) => assert!(String::from_utf8(info).unwrap().contains("kvs_version:")),
+        v => panic!("unexpected reply {:?}", v),
+    }
+
+    // errors
+    let is_err = |v: &Value, msg: &str| matches!(v, Value::Error(e) if e.starts_with(msg));
+    assert!(is_err(
+        &cli.call(&[b"GET"]),
+        "ERR wrong number of arguments"
+    ));
+    assert!(is_err(
+        &cli.call(&[b"SET", b"k"]),
+        "ERR wrong number of arguments"
+    ));
+    assert!(is_err(
+        &cli.call(&[b"SET", b"k", b"v", b"XX"]),
+        "ERR syntax error"
+    ));
+    assert!(is_err(
+        &cli.call(&[b"MSET", b"k", b"v", b"k2"]),
+        "ERR wrong number of arguments"
+    ));
+    assert!(is_err(&cli.call(&[b"FLUSHALL"]), "ERR unknown command"));
+    assert!(is_err(&cli.call(&[b"SCAN", b"42"]), "ERR invalid cursor"));
+    assert!(is_err(
+        &cli.call(&[b"SCAN", b"0", b"COUNT"]),
+        "ERR syntax error"
+    ));
+
+    // inline and pipelined commands
+    cli.writer
+        .write_all(b"SET inline value\r\nGET inline\r\n\r\nPING\r\n")
+        .unwrap();
+    assert_eq!(cli.recv(), ok());
+    assert_eq!(cli.recv(), bulk(b"value"));
+    assert_eq!(cli.recv(), Value::Simple("PONG".to_owned()));
+
+    // QUIT replies then closes
+    assert_eq!(cli.call(&[b"QUIT"]), ok());
+    let mut rest = Vec::new();
+    cli.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // a protocol error is replied then the connection is closed
+    let mut cli = RespClient::connect(addr);
+    cli.writer.write_all(b"*1\r\n+PING\r\n").unwrap();
+    assert!(matches!(cli.recv(), Value::Error(e) if e.contains("Protocol error")));
+    let mut rest = Vec::new();
+    cli.reader.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    stop_server(server);
+}
+
+// SET with EX or PX should expire the key
+#[test]
+fn resp_set_expiry() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4042";
+    let server = start_server(&temp_dir, addr);
+    let mut cli = RespClient::connect(addr);
+
+    assert_eq!(cli.call(&[b"SET", b"px", b"v", b"PX", b"300"]), ok());
+    assert_eq!(cli.call(&[b"SET", b"ex", b"v", b"ex", b"3600"]), ok());
+    assert_eq!(cli.call(&[b"GET", b"px"]), bulk(b"v"));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(cli.call(&[b"GET", b"px"]), Value::Nil);
+    assert_eq!(cli.call(&[b"EXISTS", b"px", b"ex"]), Value::Integer(1));
+
+    let is_err = |v: &Value, msg: &str| matches!(v, Value::Error(e) if e.starts_with(msg));
+    assert!(is_err(
+        &cli.call(&[b"SET", b"k", b"v", b"EX", b"0"]),
+        "ERR invalid expire time"
+    ));
+    assert!(is_err(
+        &cli.call(&[b"SET", b"k", b"v", b"PX", b"soon"]),
+        "ERR invalid expire time"
+    ));
+    assert!(is_err(
+        &cli.call(&[b"SET", b"k", b"v", b"KEEP", b"1"]),
+        "ERR syntax error"
+    ));
+    assert_eq!(cli.call(&[b"GET", b"k"]), Value::Nil);
+
+    stop_server(server);
+}
+
+// SCAN should return every key exactly once, filtered by MATCH
+#[test]
+fn resp_scan() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4017";
+    let server = start_server(&temp_dir, addr);
+    let mut cli = RespClient::connect(addr);
+
+    let mut all = BTreeSet::new();
+    for i in 0..100 {
+        for prefix in ["user:", "order:", "item"] {
+            let key = format!("{}{}", prefix, i).into_bytes();
+            assert_eq!(cli.call(&[b"SET", &key, b"v"]), ok());
+            all.insert(key);
+        }
+    }
+    let expect = |f: &dyn Fn(&str) -> bool| -> Vec<Vec<u8>> {
+        all.iter()
+            .filter(|k| f(std::str::from_utf8(k).unwrap()))
+            .cloned()
+            .collect()
+    };
+
+    assert_eq!(cli.scan_all(&[]), expect(&|_| true));
+    assert_eq!(cli.scan_all(&[b"COUNT", b"7"]), expect(&|_| true));
+    assert_eq!(
+        cli.scan_all(&[b"MATCH", b"user:*"]),
+        expect(&|k| k.starts_with("user:"))
+    );
+    assert_eq!(
+        cli.scan_all(&[b"MATCH", b"*:1?", b"COUNT", b"1000"]),
+        expect(&|k| k
+            .split_once(':')
+            .is_some_and(|(_, n)| n.len() == 2 && n.starts_with('1')))
+    );
+    assert_eq!(
+        cli.scan_all(&[b"MATCH", b"item[1-3]"]),
+        vec![b"item1".to_vec(), b"item2".to_vec(), b"item3".to_vec()]
+    );
+    assert_eq!(
+        cli.scan_all(&[b"MATCH", b"order:[^0-8]"]),
+        vec![b"order:9".to_vec()]
+    );
+    assert_eq!(
+        cli.scan_all(&[b"MATCH", b"it\\em5*"]),
+        vec![b"item5".to_vec()]
+            .into_iter()
+            .chain((50..60).map(|i| format!("item{}", i).into_bytes()))
+            .collect::<Vec<_>>()
+    );
+    assert!(cli.scan_all(&[b"MATCH", b"nothing*"]).is_empty());
+    // COUNT is capped, a huge one still returns a page and a cursor
+    match cli.call(&[b"SCAN", b"0", b"COUNT", b"1000000"]) {
+        Value::Array(items) => match &items[..] {
+            [Value::Bulk(next), Value::Array(page)] => {
+                assert_ne!(next, b"0");
+                assert!(page.len() < all.len());
+            }
+            _ => panic!("unexpected reply {:?}", items),
+        },
+        v => panic!("unexpected reply {:?}", v),
+    }
+
+    stop_server(server);
+}
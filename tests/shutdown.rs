@@ -0,0 +1,120 @@
+use assert_cmd::prelude::*;
+use kvs::client::{AsyncClient, Client};
+use kvs::server::{self, ServerOptions};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, Result, SyncPolicy};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, timeout};
+
+// Idle connections should be closed, and the store closed before `run` returns
+#[tokio::test]
+async fn run_until_shutdown() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4028";
+    let opts = KvStoreOptions::new().sync(SyncPolicy::Never);
+    let store = KvStore::open(temp_dir.path(), SharedQueueThreadPool::new(4)?, opts)?;
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(server::run(addr, store, ServerOptions::new(), async {
+        let _ = stopped.await;
+    }));
+    sleep(Duration::from_millis(200)).await;
+
+    let mut client = AsyncClient::connect(addr).await?;
+    for i in 0..100 {
+        let key = format!("key{}", i).into_bytes();
+        client.set(key, b"value".to_vec()).await?;
+    }
+    stop.send(()).unwrap();
+    timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server should stop in time")??;
+    assert!(client.get(b"key0".to_vec()).await.is_err());
+    assert!(AsyncClient::connect(addr).await.is_err());
+
+    let store = KvStore::open(
+        temp_dir.path(),
+        SharedQueueThreadPool::new(1)?,
+        KvStoreOptions::new(),
+    )?;
+    assert_eq!(store.get(b"key99".to_vec()).await?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Connections still busy after the drain timeout should be dropped
+#[tokio::test]
+async fn drain_timeout() -> Result<()> {
+    let temp_dir = TempDir::new()?;
+    let addr = "127.0.0.1:4029";
+    let store = KvStore::open(
+        temp_dir.path(),
+        SharedQueueThreadPool::new(4)?,
+        KvStoreOptions::new(),
+    )?;
+    let opts = ServerOptions::new().drain_timeout(Duration::from_millis(200));
+    let (stop, stopped) = oneshot::channel::<()>();
+    let server = tokio::spawn(server::run(addr, store, opts, async {
+        let _ = stopped.await;
+    }));
+    sleep(Duration::from_millis(200)).await;
+
+    // half a legacy request keeps its connection busy
+    let mut stream = tokio::net::TcpStream::connect(addr).await?;
+    tokio::io::AsyncWriteExt::write_all(&mut stream, b"+\0\0\0\x03ke").await?;
+    sleep(Duration::from_millis(100)).await;
+    let start = Instant::now();
+    stop.send(()).unwrap();
+    timeout(Duration::from_secs(5), server)
+        .await
+        .expect("server should stop in time")??;
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    Ok(())
+}
+
+// SIGTERM should stop kvs-server with success, keeping written data
+#[cfg(unix)]
+#[test]
+fn sigterm() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4030";
+    let start = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", addr, "--sync", "never"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let mut child = start();
+    let mut client = Client::connect(addr).unwrap();
+    client.set(b"key".to_vec(), b"value".to_vec()).unwrap();
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "server should stop in time");
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+
+    let mut child = start();
+    let mut client = Client::connect(addr).unwrap();
+    assert_eq!(
+        client.get(b"key".to_vec()).unwrap(),
+        Some(b"value".to_vec())
+    );
+    child.kill().unwrap();
+    child.wait().unwrap();
+}
@@ -0,0 +1,251 @@
+use failure::err_msg;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvStoreOptions, KvsEngine, MyErr, Result, SledKvsEngine, WriteBatch};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn open_store(dir: &TempDir) -> Result<KvStore<SharedQueueThreadPool>> {
+    let opts = KvStoreOptions::new()
+        .segment_size(1024)
+        .compact_threshold(2048)
+        .compact_check(Duration::from_millis(100));
+    KvStore::open(dir.path(), SharedQueueThreadPool::new(4)?, opts)
+}
+
+fn num(val: Option<Vec<u8>>) -> u64 {
+    val.map_or(0, |v| String::from_utf8(v).unwrap().parse().unwrap())
+}
+
+// Writes should be seen by later reads of the transaction, and by others only
+// if it commits
+async fn read_own_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.clone().set("key1".into(), "value1".into()).await?;
+    let val = engine
+        .clone()
+        .transaction(|tx| {
+            assert_eq!(tx.get("key1".into())?, Some("value1".into()));
+            tx.set("key1".into(), "value2".into())?;
+            tx.set("key2".into(), "value2".into())?;
+            tx.remove("key2".into())?;
+            tx.remove("absent".into())?;
+            assert_eq!(tx.get("key2".into())?, None);
+            tx.get("key1".into())
+        })
+        .await?;
+    assert_eq!(val, Some("value2".into()));
+    assert_eq!(
+        engine.clone().get("key1".into()).await?,
+        Some("value2".into())
+    );
+    assert_eq!(engine.clone().get("key2".into()).await?, None);
+
+    // an error aborts the transaction
+    let rlt = engine
+        .clone()
+        .transaction(|tx| {
+            tx.set("key1".into(), "value3".into())?;
+            Err::<(), _>(err_msg("abort"))
+        })
+        .await;
+    assert_eq!(rlt.unwrap_err().to_string(), "abort");
+    assert_eq!(
+        engine.clone().get("key1".into()).await?,
+        Some("value2".into())
+    );
+
+    // an expired value is absent
+    engine
+        .clone()
+        .set_with_ttl("temp".into(), "value".into(), Duration::from_millis(100))
+        .await?;
+    thread::sleep(Duration::from_millis(200));
+    let val = engine
+        .clone()
+        .transaction(|tx| tx.get("temp".into()))
+        .await?;
+    assert_eq!(val, None);
+    Ok(())
+}
+
+// Racing transfers between two keys should never lose an update
+async fn concurrent_transfers<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.clone().set("a".into(), "1000".into()).await?;
+    let (tasks, transfers) = (8, 25);
+    let handles: Vec<_> = (0..tasks)
+        .map(|_| {
+            let engine = engine.clone();
+            tokio::spawn(async move {
+                for _ in 0..transfers {
+                    engine
+                        .clone()
+                        .transaction(|tx| {
+                            let a = num(tx.get("a".into())?);
+                            let b = num(tx.get("b".into())?);
+                            tx.set("a".into(), (a - 1).to_string().into_bytes())?;
+                            tx.set("b".into(), (b + 1).to_string().into_bytes())
+                        })
+                        .await?;
+                }
+                Result::Ok(())
+            })
+        })
+        .collect();
+    for h in handles {
+        h.await??;
+    }
+    let moved = (tasks * transfers) as u64;
+    assert_eq!(num(engine.clone().get("a".into()).await?), 1000 - moved);
+    assert_eq!(num(engine.clone().get("b".into()).await?), moved);
+    Ok(())
+}
+
+#[tokio::test]
+async fn read_own_writes_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    read_own_writes(open_store(&temp_dir)?).await
+}
+
+#[tokio::test]
+async fn read_own_writes_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    read_own_writes(SledKvsEngine::open(temp_dir.path())?).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_transfers_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_transfers(open_store(&temp_dir)?).await
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_transfers_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    concurrent_transfers(SledKvsEngine::open(temp_dir.path())?).await
+}
+
+// A key written by others after it is read should fail the commit, then the
+// transaction is run again on the new value
+#[tokio::test]
+async fn retry_on_conflict() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("counter".into(), "1".into()).await?;
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (other, tried) = (store.clone(), attempts.clone());
+    let val = store
+        .clone()
+        .transaction(move |tx| {
+            let n = num(tx.get("counter".into())?);
+            if tried.fetch_add(1, Ordering::SeqCst) == 0 {
+                let other = other.clone();
+                thread::spawn(move || {
+                    let rt = tokio::runtime::Runtime::new().unwrap();
+                    rt.block_on(other.set("counter".into(), "10".into()))
+                })
+                .join()
+                .unwrap()?;
+            }
+            tx.set("counter".into(), (n + 1).to_string().into_bytes())?;
+            Ok(n + 1)
+        })
+        .await?;
+    assert_eq!(val, 11);
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    drop(store);
+    let store = open_store(&temp_dir)?;
+    assert_eq!(num(store.clone().get("counter".into()).await?), 11);
+    Ok(())
+}
+
+// A transaction conflicting on every attempt should give up after a bounded
+// number of them, leaving the value written by others
+#[tokio::test]
+async fn give_up_on_conflicts() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    store.clone().set("counter".into(), "0".into()).await?;
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (other, tried) = (store.clone(), attempts.clone());
+    let rlt = store
+        .clone()
+        .transaction(move |tx| {
+            let n = num(tx.get("counter".into())?);
+            let other = other.clone();
+            let conflicting = (tried.fetch_add(1, Ordering::SeqCst) + 100).to_string();
+            thread::spawn(move || {
+                let rt = tokio::runtime::Runtime::new().unwrap();
+                rt.block_on(other.set("counter".into(), conflicting.into_bytes()))
+            })
+            .join()
+            .unwrap()?;
+            tx.set("counter".into(), (n + 1).to_string().into_bytes())
+        })
+        .await;
+    let err = rlt.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(MyErr::Conflict(_))));
+    let tried = attempts.load(Ordering::SeqCst);
+    assert!(tried > 1 && tried < 100);
+    assert_eq!(
+        num(store.clone().get("counter".into()).await?),
+        tried as u64 + 99
+    );
+    Ok(())
+}
+
+// A transaction failing on keys read from different commits should be run
+// again, its error is returned only if its reads are current
+#[tokio::test]
+async fn retry_failure_on_stale_reads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_store(&temp_dir)?;
+    let mut batch = WriteBatch::new();
+    batch.set("a".into(), "5".into());
+    batch.set("b".into(), "5".into());
+    store.clone().apply_batch(batch).await?;
+
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let (other, tried) = (store.clone(), attempts.clone());
+    let sum = store
+        .clone()
+        .transaction(move |tx| {
+            let a = num(tx.get("a".into())?);
+            if tried.fetch_add(1, Ordering::SeqCst) == 0 {
+                // a transfer committed between the reads of `a` and `b`
+                let other = other.clone();
+                thread::spawn(move || {
+                    let mut batch = WriteBatch::new();
+                    batch.set("a".into(), "4".into());
+                    batch.set("b".into(), "6".into());
+                    let rt = tokio::runtime::Runtime::new().unwrap();
+                    rt.block_on(other.apply_batch(batch))
+                })
+                .join()
+                .unwrap()?;
+            }
+            let b = num(tx.get("b".into())?);
+            if a + b != 10 {
+                return Err(err_msg("inconsistent"));
+            }
+            Ok(a + b)
+        })
+        .await?;
+    assert_eq!(sum, 10);
+    assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+    // a failure on current reads is returned
+    let rlt = store
+        .clone()
+        .transaction(|tx| {
+            tx.get("a".into())?;
+            Err::<(), _>(err_msg("abort"))
+        })
+        .await;
+    assert_eq!(rlt.unwrap_err().to_string(), "abort");
+    Ok(())
+}